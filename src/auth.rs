@@ -0,0 +1,267 @@
+extern crate crypto;
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::{HeaderMap, Uri};
+use http::header::AUTHORIZATION;
+
+use crate::proxy::percent_decode;
+use crate::wsproto::Error;
+
+use self::crypto::hmac::Hmac;
+use self::crypto::mac::Mac;
+use self::crypto::sha2::Sha256;
+use self::crypto::util::fixed_time_eq;
+
+/// Who a connection was authenticated as.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity(pub String);
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// One way of authenticating an upgrade request.
+///
+/// Returns `Ok(None)` when the request carries no credentials of this kind,
+/// so the next authenticator gets a go, and an error when they are wrong.
+pub trait Authenticator: fmt::Debug + Send + Sync {
+    fn authenticate(&self, uri: &Uri, headers: &HeaderMap) -> Result<Option<Identity>, Error>;
+}
+
+/// Tries each authenticator in turn. With none configured everyone is let in
+/// anonymously.
+pub fn authenticate(authenticators: &[std::sync::Arc<dyn Authenticator>], uri: &Uri, headers: &HeaderMap) -> Result<Option<Identity>, Error> {
+    if authenticators.is_empty() {
+        return Ok(None);
+    }
+    for authenticator in authenticators {
+        if let Some(identity) = authenticator.authenticate(uri, headers)? {
+            return Ok(Some(identity));
+        }
+    }
+    Err(Error::Auth("no credentials".into()))
+}
+
+/// Compares secrets in time independent of where they differ.
+fn secret_eq(a: &str, b: &str) -> bool {
+    // fixed_time_eq reads the first byte unchecked
+    !a.is_empty() && fixed_time_eq(a.as_bytes(), b.as_bytes())
+}
+
+/// Looks a token up in a list of (token, identity) pairs.
+fn find_token(tokens: &[(String, Identity)], token: &str) -> Result<Option<Identity>, Error> {
+    tokens.iter()
+        .find(|(t, _)| secret_eq(t, token))
+        .map(|(_, identity)| Some(identity.clone()))
+        .ok_or_else(|| Error::Auth("unknown token".into()))
+}
+
+fn query_param<'a>(uri: &'a Uri, name: &str) -> Option<&'a str> {
+    uri.query()?
+        .split('&')
+        .filter_map(|param| {
+            let mut kv = param.splitn(2, '=');
+            Some((kv.next()?, kv.next().unwrap_or("")))
+        })
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+/// Static tokens sent as `Authorization: Bearer <token>`.
+// the authenticators are for whoever builds a ServerConfig, the demo
+// servers in `main` run without any
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct BearerTokens {
+    pub tokens: Vec<(String, Identity)>,
+}
+
+impl Authenticator for BearerTokens {
+    fn authenticate(&self, _uri: &Uri, headers: &HeaderMap) -> Result<Option<Identity>, Error> {
+        let value = match headers.get(AUTHORIZATION) {
+            Some(v) => v.to_str().map_err(|_| Error::Auth("bad authorization header".into()))?,
+            None => return Ok(None),
+        };
+        let mut parts = value.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                find_token(&self.tokens, token.trim())
+            },
+            // some other scheme, maybe for another authenticator
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Static tokens passed in a query parameter, for browsers, which cannot set
+/// headers on a WebSocket request.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct QueryToken {
+    pub param: String,
+    pub tokens: Vec<(String, Identity)>,
+}
+
+impl Authenticator for QueryToken {
+    fn authenticate(&self, uri: &Uri, _headers: &HeaderMap) -> Result<Option<Identity>, Error> {
+        match query_param(uri, &self.param) {
+            Some(token) => find_token(&self.tokens, &percent_decode(token)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// URLs handed out by another service, valid for one user until they expire.
+///
+/// A signed URL ends in `user=<id>&expires=<unix time>&sig=<hex>`, where the
+/// signature is HMAC-SHA256 over everything in the path and query before `&sig=`.
+/// Only these last three fields count: parameters of the same names earlier
+/// in the query were signed as part of the path, not as the user or expiry.
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct SignedUrl {
+    pub secret: Vec<u8>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Splits the last query parameter off `s` if it is called `name`,
+/// returning what comes before it and its value.
+fn split_last_param<'a>(s: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let i = s.rfind(['&', '?'])?;
+    let value = s[i + 1..].strip_prefix(name)?.strip_prefix('=')?;
+    Some((&s[..i], value))
+}
+
+impl SignedUrl {
+    fn signature(&self, message: &str) -> String {
+        let mut mac = Hmac::new(Sha256::new(), &self.secret);
+        mac.input(message.as_bytes());
+        to_hex(mac.result().code())
+    }
+
+    /// Signs `path` for `user`, valid until `expires` seconds since the epoch.
+    #[cfg(test)]
+    pub fn sign(&self, path: &str, user: &str, expires: u64) -> String {
+        let sep = if path.contains('?') { '&' } else { '?' };
+        let user = user.bytes().map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        }).collect::<String>();
+        let unsigned = format!("{}{}user={}&expires={}", path, sep, user, expires);
+        let sig = self.signature(&unsigned);
+        format!("{}&sig={}", unsigned, sig)
+    }
+
+    fn verify(&self, uri: &Uri, now: u64) -> Result<Option<Identity>, Error> {
+        let target = match uri.path_and_query() {
+            Some(target) => target.as_str(),
+            None => return Ok(None),
+        };
+        let (unsigned, sig) = match split_last_param(target, "sig") {
+            Some((unsigned, sig)) if target[unsigned.len()..].starts_with('&') => (unsigned, sig),
+            _ => return Ok(None),
+        };
+        if !secret_eq(&self.signature(unsigned), sig) {
+            return Err(Error::Auth("bad url signature".into()));
+        }
+        let (rest, expires) = split_last_param(unsigned, "expires")
+            .ok_or_else(|| Error::Auth("signed url without expiry".into()))?;
+        let expires = expires.parse::<u64>()
+            .map_err(|_| Error::Auth("bad signed url expiry".into()))?;
+        if expires <= now {
+            return Err(Error::Auth("signed url expired".into()));
+        }
+        let (_, user) = split_last_param(rest, "user")
+            .ok_or_else(|| Error::Auth("signed url without user".into()))?;
+        Ok(Some(Identity(percent_decode(user))))
+    }
+}
+
+impl Authenticator for SignedUrl {
+    fn authenticate(&self, uri: &Uri, _headers: &HeaderMap) -> Result<Option<Identity>, Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.verify(uri, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use http::HeaderValue;
+
+    fn tokens() -> Vec<(String, Identity)> {
+        vec![("s3cret".to_owned(), Identity("alice".to_owned()))]
+    }
+
+    #[test]
+    fn test_bearer() {
+        let auth = BearerTokens { tokens: tokens() };
+        let uri: Uri = "/brg".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        assert_eq!(auth.authenticate(&uri, &headers).unwrap(), None);
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer s3cret"));
+        assert_eq!(auth.authenticate(&uri, &headers).unwrap(), Some(Identity("alice".to_owned())));
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer wrong"));
+        assert!(auth.authenticate(&uri, &headers).is_err());
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Basic YWxpY2U6cHc="));
+        assert_eq!(auth.authenticate(&uri, &headers).unwrap(), None);
+    }
+
+    #[test]
+    fn test_query_token() {
+        let auth = QueryToken { param: "token".to_owned(), tokens: tokens() };
+        let headers = HeaderMap::new();
+        let check = |uri: &str| auth.authenticate(&uri.parse().unwrap(), &headers);
+        assert_eq!(check("/brg?a=1&token=s3cret").unwrap(), Some(Identity("alice".to_owned())));
+        assert_eq!(check("/brg?token=s3%63ret").unwrap(), Some(Identity("alice".to_owned())));
+        assert!(check("/brg?token=").is_err());
+        assert_eq!(check("/brg?tokens=s3cret").unwrap(), None);
+    }
+
+    #[test]
+    fn test_signed_url() {
+        let auth = SignedUrl { secret: b"key".to_vec() };
+        let url = auth.sign("/brg", "bob", 1000);
+        let uri: Uri = url.parse().unwrap();
+        assert_eq!(auth.verify(&uri, 999).unwrap(), Some(Identity("bob".to_owned())));
+        assert!(auth.verify(&uri, 1000).is_err());
+        // changing anything that was signed breaks the signature
+        let tampered: Uri = url.replace("user=bob", "user=eve").parse().unwrap();
+        assert!(auth.verify(&tampered, 999).is_err());
+        let other = SignedUrl { secret: b"other".to_vec() };
+        assert!(other.verify(&uri, 999).is_err());
+        assert_eq!(auth.verify(&"/brg?user=bob".parse().unwrap(), 999).unwrap(), None);
+
+        // only the trailing fields are the signed user and expiry
+        let url = auth.sign("/brg?user=eve&expires=99999", "bob smith", 1000);
+        let uri: Uri = url.parse().unwrap();
+        assert_eq!(auth.verify(&uri, 999).unwrap(), Some(Identity("bob smith".to_owned())));
+        assert!(auth.verify(&uri, 1000).is_err());
+        let extended: Uri = format!("{}&expires=99999", url).parse().unwrap();
+        assert_eq!(auth.verify(&extended, 1000).unwrap(), None);
+        let extended: Uri = url.replace("&sig=", "&expires=99999&sig=").parse().unwrap();
+        assert!(auth.verify(&extended, 1000).is_err());
+    }
+
+    #[test]
+    fn test_authenticate_chain() {
+        let authenticators: Vec<Arc<dyn Authenticator>> = vec![
+            Arc::new(BearerTokens { tokens: tokens() }),
+            Arc::new(QueryToken { param: "token".to_owned(), tokens: tokens() }),
+        ];
+        let headers = HeaderMap::new();
+        let uri: Uri = "/brg?token=s3cret".parse().unwrap();
+        assert_eq!(authenticate(&authenticators, &uri, &headers).unwrap(), Some(Identity("alice".to_owned())));
+        let uri: Uri = "/brg".parse().unwrap();
+        assert!(authenticate(&authenticators, &uri, &headers).is_err());
+        assert_eq!(authenticate(&[], &uri, &headers).unwrap(), None);
+    }
+}
@@ -0,0 +1,110 @@
+//! Settings for the bridge server and the routes it serves.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+
+use crate::auth::Authenticator;
+use crate::forwarded::Cidr;
+use crate::handshake::OriginPolicy;
+use crate::tls::ServerTlsConfig;
+use crate::wsproto::{DeflateConfig, Error, KeepAlive, Limits};
+use crate::ws_msg::BrgMsgFormat;
+
+/// A bridge profile served under one URL path, so one port can front many
+/// services. Unset settings fall back to the server wide ones.
+#[derive(Debug, Clone)]
+pub struct Route {
+    /// Request path, matched exactly, e.g. `/dns`.
+    pub path: String,
+    /// Where this route's datagrams are bridged to over UDP.
+    pub target: SocketAddr,
+    pub auth: Option<Vec<Arc<dyn Authenticator>>>,
+    pub limits: Option<Limits>,
+    pub subprotocols: Option<Vec<BrgMsgFormat>>,
+}
+
+impl Route {
+    // the demo servers in `main` have no routes
+    #[allow(dead_code)]
+    pub fn new(path: &str, target: SocketAddr) -> Route {
+        Route {
+            path: path.to_owned(),
+            target,
+            auth: None,
+            limits: None,
+            subprotocols: None,
+        }
+    }
+}
+
+/// Settings for the bridge server.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub addr: SocketAddr,
+    /// Certificate and key to serve wss:// with, or `None` for plain ws://.
+    pub tls: Option<ServerTlsConfig>,
+    /// Size limits applied to every connection.
+    pub limits: Limits,
+    /// Our permessage-deflate preferences, or `None` to never negotiate it.
+    pub deflate: Option<DeflateConfig>,
+    /// Browser origins allowed to connect.
+    pub origins: OriginPolicy,
+    /// Upgrades accepted by path. Empty to accept every path with the settings below.
+    pub routes: Vec<Route>,
+    /// Reverse proxies whose `X-Forwarded-For` and `Forwarded` headers are
+    /// believed. Empty to always use the peer address.
+    pub trusted_proxies: Vec<Cidr>,
+    /// Expect a PROXY protocol header on every connection. Only enable
+    /// behind a balancer that always sends one.
+    pub proxy_protocol: bool,
+    /// Ways clients may authenticate, tried in order. Empty to let anyone in.
+    pub auth: Vec<Arc<dyn Authenticator>>,
+    /// Bridge wire formats we accept, offered as subprotocols. Clients that
+    /// offer none of them are refused.
+    pub subprotocols: Vec<BrgMsgFormat>,
+    /// Largest payload sent in a single frame, bigger messages are fragmented.
+    pub max_frame_size: Option<usize>,
+    /// Ping schedule for dead peer detection, or `None` to never ping.
+    pub keepalive: Option<KeepAlive>,
+    /// Messages a connection queues for a slow peer before it stops reading
+    /// from it and pushes back on bridged senders.
+    pub max_queued: usize,
+    /// Reported by the readiness endpoint. Set once the listener is bound,
+    /// clear it to drain the bridge out of a load balancer.
+    pub ready: Arc<AtomicBool>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            addr: "0.0.0.0:8080".parse::<SocketAddr>().unwrap(),
+            tls: None,
+            limits: Limits::default(),
+            deflate: Some(DeflateConfig::default()),
+            origins: OriginPolicy::default(),
+            routes: Vec::new(),
+            trusted_proxies: Vec::new(),
+            proxy_protocol: false,
+            auth: Vec::new(),
+            subprotocols: vec![BrgMsgFormat::Binary, BrgMsgFormat::Base64],
+            max_frame_size: None,
+            keepalive: Some(KeepAlive::default()),
+            max_queued: 64,
+            ready: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The route serving upgrades to `path`, `None` when no routes are configured.
+    pub fn route(&self, path: &str) -> Result<Option<&Route>, Error> {
+        if self.routes.is_empty() {
+            return Ok(None);
+        }
+        self.routes.iter()
+            .find(|route| route.path == path)
+            .map(Some)
+            .ok_or_else(|| Error::NotFound(path.to_owned().into()))
+    }
+}
@@ -0,0 +1,85 @@
+//! Plain HTTP endpoints served next to the WebSocket upgrade, so load
+//! balancers and uptime checks can probe the bridge port directly.
+
+use std::sync::atomic::Ordering;
+
+use http::{HeaderValue, Method, Request, Response, StatusCode};
+use http::header::{ALLOW, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, UPGRADE};
+
+use crate::config::ServerConfig;
+
+/// Liveness, answered as long as the process is serving requests.
+pub const HEALTH_PATH: &str = "/healthz";
+/// Readiness, see `ServerConfig::ready`.
+pub const READY_PATH: &str = "/readyz";
+/// Package name and version.
+pub const VERSION_PATH: &str = "/version";
+
+/// Whether a request asks for a protocol upgrade rather than a plain response.
+pub fn is_upgrade<B>(req: &Request<B>) -> bool {
+    req.headers().contains_key(UPGRADE)
+}
+
+/// Answers a request that is not an upgrade.
+pub fn endpoint<B>(req: &Request<B>, config: &ServerConfig) -> Response<String> {
+    let (status, body) = match req.uri().path() {
+        HEALTH_PATH => (StatusCode::OK, "ok\n".to_owned()),
+        READY_PATH if config.ready.load(Ordering::SeqCst) => (StatusCode::OK, "ready\n".to_owned()),
+        READY_PATH => (StatusCode::SERVICE_UNAVAILABLE, "not ready\n".to_owned()),
+        VERSION_PATH => (StatusCode::OK, format!("{} {}\n", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))),
+        _ => (StatusCode::NOT_FOUND, "not found\n".to_owned()),
+    };
+    let (status, body) = match *req.method() {
+        Method::GET | Method::HEAD => (status, body),
+        _ => (StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n".to_owned()),
+    };
+    let mut res = Response::new(String::new());
+    *res.status_mut() = status;
+    let headers = res.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    headers.insert(CONNECTION, HeaderValue::from_static("close"));
+    if status == StatusCode::METHOD_NOT_ALLOWED {
+        headers.insert(ALLOW, HeaderValue::from_static("GET, HEAD"));
+    }
+    // a HEAD response carries the length of the body it leaves out
+    if req.method() != Method::HEAD {
+        *res.body_mut() = body;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(method: Method, path: &str, config: &ServerConfig) -> Response<String> {
+        let mut req = Request::new(());
+        *req.method_mut() = method;
+        *req.uri_mut() = path.parse().unwrap();
+        endpoint(&req, config)
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let config = ServerConfig::default();
+        let res = get(Method::GET, HEALTH_PATH, &config);
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "ok\n");
+
+        assert_eq!(get(Method::GET, READY_PATH, &config).status(), StatusCode::SERVICE_UNAVAILABLE);
+        config.ready.store(true, Ordering::SeqCst);
+        assert_eq!(get(Method::GET, READY_PATH, &config).status(), StatusCode::OK);
+
+        let res = get(Method::HEAD, VERSION_PATH, &config);
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "");
+        let len = format!("{} {}\n", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")).len();
+        assert_eq!(res.headers()[CONTENT_LENGTH], HeaderValue::from(len));
+
+        assert_eq!(get(Method::GET, "/brg", &config).status(), StatusCode::NOT_FOUND);
+        let res = get(Method::POST, HEALTH_PATH, &config);
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()[ALLOW], "GET, HEAD");
+    }
+}
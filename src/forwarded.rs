@@ -0,0 +1,165 @@
+//! Finding the real client address behind trusted reverse proxies.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use http::HeaderMap;
+use http::header::FORWARDED;
+
+/// An IP network, e.g. `10.0.0.0/8` or `fd00::/8`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // compare IPv4 clients of dual stack sockets as IPv4
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let bad = || format!("bad cidr: {}", s);
+        let (addr, prefix) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| bad())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().ok().filter(|&p| p <= max).ok_or_else(bad)?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Parses a forwarded address, which may carry a port or IPv6 brackets.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse::<IpAddr>().ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|a| a.ip()))
+        .or_else(|| node.trim_start_matches('[').trim_end_matches(']').parse().ok())
+}
+
+/// The `for=` nodes of `Forwarded` (RFC 7239), or else `X-Forwarded-For`,
+/// from the first hop to the last. Entries that are not addresses, like
+/// `unknown` or obfuscated names, are `None`.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name: &str| headers.get_all(name).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    let forwarded = values(FORWARDED.as_str());
+    if !forwarded.is_empty() {
+        return forwarded.iter()
+            .map(|element| {
+                element.split(';')
+                    .filter_map(|pair| {
+                        let mut kv = pair.splitn(2, '=');
+                        Some((kv.next()?.trim(), kv.next()?))
+                    })
+                    .find(|(k, _)| k.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, v)| parse_node(v))
+            })
+            .collect();
+    }
+    values("x-forwarded-for").iter().map(|node| parse_node(node)).collect()
+}
+
+/// The address of the client, as far back as trusted proxies vouch for it.
+///
+/// Forwarding headers are only read when `peer` is one of the `trusted`
+/// proxies, and then from the last hop back, stopping at the first address
+/// not in `trusted`, as anything before it could have been made up.
+pub fn client_addr(peer: IpAddr, headers: &HeaderMap, trusted: &[Cidr]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|net| net.contains(ip));
+    let mut client = peer;
+    if !is_trusted(peer) {
+        return client;
+    }
+    for node in forwarded_for(headers).into_iter().rev() {
+        match node {
+            Some(ip) => client = ip,
+            None => break,
+        }
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn trusted() -> Vec<Cidr> {
+        vec!["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()]
+    }
+
+    #[test]
+    fn test_cidr() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.200.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.0.1".parse().unwrap()));
+        let all: Cidr = "::/0".parse().unwrap();
+        assert!(all.contains("2001:db8::1".parse().unwrap()));
+        assert!(!all.contains("10.1.0.1".parse().unwrap()));
+        assert_eq!(all.to_string(), "::/0");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_x_forwarded_for() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("6.6.6.6, 203.0.113.7, 10.0.0.2"));
+        let addr = |headers: &HeaderMap, peer: &str| client_addr(peer.parse().unwrap(), headers, &trusted()).to_string();
+        // the spoofed first entry is ignored
+        assert_eq!(addr(&headers, "127.0.0.1"), "203.0.113.7");
+        // headers from untrusted peers are ignored
+        assert_eq!(addr(&headers, "198.51.100.1"), "198.51.100.1");
+        headers.insert("x-forwarded-for", HeaderValue::from_static("unknown, 10.0.0.3"));
+        assert_eq!(addr(&headers, "127.0.0.1"), "10.0.0.3");
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("6.6.6.6"));
+        headers.insert(FORWARDED, HeaderValue::from_static(
+            "for=192.0.2.60;proto=http, For=\"[2001:db8:cafe::17]:4711\";by=10.0.0.1, for=10.0.0.9:1234"
+        ));
+        let addr = client_addr("10.0.0.1".parse().unwrap(), &headers, &trusted());
+        assert_eq!(addr, "2001:db8:cafe::17".parse::<IpAddr>().unwrap());
+    }
+}
@@ -0,0 +1,1012 @@
+extern crate base64;
+extern crate crypto;
+extern crate httparse;
+
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use bytes::{BufMut, BytesMut};
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Version};
+use http::header::{HeaderName, ALLOW, HOST, ORIGIN, UPGRADE, WWW_AUTHENTICATE, SEC_WEBSOCKET_VERSION, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_PROTOCOL, CONNECTION, CONTENT_LENGTH};
+use tokio::codec::{Decoder, Encoder, Framed, FramedParts};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::auth::{authenticate, Identity};
+use crate::config::{Route, ServerConfig};
+use crate::endpoints::{endpoint, is_upgrade};
+use crate::forwarded::client_addr;
+use crate::proxy::Proxy;
+use crate::tls::ClientTlsConfig;
+use crate::wsproto::{DeflateConfig, Error, Limits, MessageCodec, WsCodec};
+use crate::ws_msg::BrgMsgFormat;
+
+use self::crypto::digest::Digest;
+
+pub fn ws_gen_accept_header(v: &str) -> String {
+    let s = format!("{}{}", v, "258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    let mut sha1 = crypto::sha1::Sha1::new();
+    sha1.input_str(s.as_str());
+    let mut sha1_bytes = [0u8; 20];
+    sha1.result(&mut sha1_bytes);
+    base64::encode(&sha1_bytes)
+}
+
+/// Picks the extensions to use from the client's offers, adding them to the response.
+fn ws_negotiate_extensions(req: &HeaderMap, res: &mut HeaderMap, config: &ServerConfig) -> Option<DeflateConfig> {
+    let local = config.deflate.as_ref()?;
+    let offers = req.get_all(SEC_WEBSOCKET_EXTENSIONS).iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let deflate = local.negotiate(&offers)?;
+    res.insert(
+        SEC_WEBSOCKET_EXTENSIONS,
+        HeaderValue::from_str(deflate.to_header().as_str()).unwrap()
+    );
+    Some(deflate)
+}
+
+/// Picks the first subprotocol the client offers that we support, adding it to the response.
+fn ws_negotiate_subprotocol(req: &HeaderMap, res: &mut HeaderMap, subprotocols: &[BrgMsgFormat]) -> Result<BrgMsgFormat, Error> {
+    let format = req.get_all(SEC_WEBSOCKET_PROTOCOL).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| BrgMsgFormat::from_subprotocol(name.trim()))
+        .find(|format| subprotocols.contains(format))
+        .ok_or_else(|| Error::Handshake("no supported sec-websocket-protocol offered".into()))?;
+    res.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(format.subprotocol()));
+    Ok(format)
+}
+
+/// Origins allowed to upgrade, by exact match or `scheme://*.domain` pattern.
+#[derive(Debug, Clone)]
+pub enum AllowedOrigins {
+    Any,
+    // the demo servers in `main` allow any origin
+    #[allow(dead_code)]
+    Only(Vec<String>),
+}
+
+/// Which browser origins may open a connection.
+#[derive(Debug, Clone)]
+pub struct OriginPolicy {
+    pub allowed: AllowedOrigins,
+    /// Whether requests without an Origin header, sent by non-browser
+    /// clients, are let through.
+    pub allow_missing: bool,
+}
+
+impl Default for OriginPolicy {
+    fn default() -> OriginPolicy {
+        OriginPolicy {
+            allowed: AllowedOrigins::Any,
+            allow_missing: true,
+        }
+    }
+}
+
+/// Matches `origin` against an exact origin or a `scheme://*.domain` pattern.
+/// A wildcard stands for one or more subdomain labels, not the domain itself.
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    let wildcard = match pattern.find("://*.") {
+        Some(i) => i,
+        None => return pattern.eq_ignore_ascii_case(origin),
+    };
+    let (scheme, suffix) = (&pattern[..wildcard + 3], &pattern[wildcard + 4..]);
+    if origin.len() <= scheme.len() + suffix.len()
+        || !origin.is_char_boundary(scheme.len())
+        || !origin[..scheme.len()].eq_ignore_ascii_case(scheme) {
+        return false;
+    }
+    let host = &origin[scheme.len()..];
+    let split = host.len() - suffix.len();
+    host.is_char_boundary(split)
+        && host[split..].eq_ignore_ascii_case(suffix)
+        && !host[..split].contains(['/', ':'])
+}
+
+impl OriginPolicy {
+    pub fn check(&self, origin: Option<&str>) -> Result<(), Error> {
+        let origin = match origin {
+            Some(origin) => origin,
+            None if self.allow_missing => return Ok(()),
+            None => return Err(Error::Policy("no origin header".into())),
+        };
+        let allowed = match self.allowed {
+            AllowedOrigins::Any => true,
+            AllowedOrigins::Only(ref patterns) => patterns.iter().any(|p| origin_matches(p, origin)),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(Error::Policy(format!("origin not allowed: {}", origin).into()))
+        }
+    }
+}
+
+/// What the opening handshake settled on.
+pub struct Negotiated {
+    pub format: BrgMsgFormat,
+    pub deflate: Option<DeflateConfig>,
+    /// Who the client authenticated as, `None` if authentication is off. A
+    /// client certificate names the client ahead of any token, though the
+    /// token must still pass.
+    pub identity: Option<Identity>,
+    /// The route the request path matched, `None` if no routes are configured.
+    pub route: Option<Route>,
+    /// The client's address, taken from forwarding headers when the peer is
+    /// a trusted proxy. `None` if the peer address is not known.
+    pub client_addr: Option<IpAddr>,
+}
+
+/// Whether any `name` header lists `token`, ignoring case.
+fn has_token(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers.get_all(name).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case(token))
+}
+
+/// Validates an upgrade request from `peer`, returning the headers of the 101 response.
+/// `client_cert` is who the TLS client certificate names, if mutual TLS verified
+/// one, and stands in for the route's authenticators.
+pub fn ws_handshake<B>(request: &Request<B>, peer: Option<IpAddr>, client_cert: Option<&Identity>, config: &ServerConfig)
+    -> Result<(HeaderMap, Negotiated), Error> {
+    let req = request.headers();
+    let mut res = HeaderMap::new();
+    if request.method() != Method::GET {
+        return Err(Error::Method(request.method().to_string().into()));
+    }
+    // reject non websocket requests
+    match req.get(UPGRADE) {
+        Some(v) if v == "websocket" => (),
+        Some(v) => return Err(Error::Handshake(format!("invalid upgrade header value: {:?}", v).into())),
+        None => return Err(Error::Handshake("no upgrade header".into())),
+    }
+    if !has_token(req, CONNECTION, "upgrade") {
+        return Err(Error::Handshake("no upgrade connection header".into()));
+    }
+    match req.get(SEC_WEBSOCKET_VERSION) {
+        Some(v) if v == "13" => (),
+        Some(v) => return Err(Error::Version(format!("{:?}", v).into())),
+        None => return Err(Error::Handshake("no sec-websocket-version header".into())),
+    }
+    let route = config.route(request.uri().path())?;
+    // only browsers send an Origin
+    let origin = match req.get(ORIGIN) {
+        Some(v) => Some(v.to_str().map_err(|_| Error::Policy(format!("bad origin header: {:?}", v).into()))?),
+        None => None,
+    };
+    config.origins.check(origin)?;
+    let auth = route.and_then(|r| r.auth.as_ref()).unwrap_or(&config.auth);
+    // a certificate verified by mutual TLS is credentials enough
+    let identity = match client_cert {
+        Some(identity) => Some(identity.clone()),
+        None => authenticate(auth, request.uri(), req)?,
+    };
+    let key = req.get(SEC_WEBSOCKET_KEY)
+        .ok_or_else(|| Error::Handshake("no sec-websocket-key header".into()))?;
+    match base64::decode(key.as_bytes()) {
+        Ok(ref k) if k.len() == 16 => (),
+        Ok(k) => return Err(Error::Handshake(
+            format!("base64 decoded sec-websocket-key length mismatch: {}", k.len()).into()
+        )),
+        Err(e) => return Err(Error::Handshake(format!("base64 decode sec-websocket-key error: {:?}", e).into())),
+    }
+    // Sec-WebSocket-Accept, the key is base64 so it is a valid header value
+    res.insert(
+        SEC_WEBSOCKET_ACCEPT,
+        HeaderValue::from_str(
+            ws_gen_accept_header(key.to_str().unwrap()).as_str()
+        ).unwrap()
+    );
+    let subprotocols = route.and_then(|r| r.subprotocols.as_ref()).unwrap_or(&config.subprotocols);
+    let format = ws_negotiate_subprotocol(req, &mut res, subprotocols)?;
+    let deflate = ws_negotiate_extensions(req, &mut res, config);
+    res.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    res.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+    let client_addr = peer.map(|peer| client_addr(peer, req, &config.trusted_proxies));
+    Ok((res, Negotiated { format, deflate, identity, route: route.cloned(), client_addr }))
+}
+
+/// The codec driving a connection once the handshake is done.
+pub fn server_codec(negotiated: &Negotiated, config: &ServerConfig) -> MessageCodec {
+    let limits = negotiated.route.as_ref().and_then(|r| r.limits.as_ref()).unwrap_or(&config.limits);
+    let mut codec = MessageCodec::new(WsCodec::new().strict(true).limits(limits.clone()));
+    if let Some(size) = config.max_frame_size {
+        codec = codec.max_frame_size(size);
+    }
+    if let Some(ref deflate) = negotiated.deflate {
+        codec = codec.deflate(deflate);
+    }
+    codec
+}
+
+/// Upper bounds on an upgrade request, which has no body.
+const MAX_HEADERS: usize = 32;
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Reads HTTP/1.1 request heads and writes response heads, just enough of
+/// HTTP for the opening handshake.
+pub struct HttpCodec;
+
+impl Decoder for HttpCodec {
+    type Item = Request<()>;
+    type Error = Error;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        let len = match req.parse(src) {
+            Ok(httparse::Status::Complete(len)) => len,
+            Ok(httparse::Status::Partial) => {
+                if src.len() > MAX_REQUEST_HEAD {
+                    return Err(Error::Capacity(format!("request head exceeds {} bytes", MAX_REQUEST_HEAD).into()));
+                }
+                return Ok(None);
+            },
+            Err(e) => return Err(Error::Handshake(format!("bad http request: {}", e).into())),
+        };
+        if req.version != Some(1) {
+            return Err(Error::Handshake("http version is not 1.1".into()));
+        }
+        let mut builder = Request::builder();
+        // both are set once parsing is complete
+        builder.method(req.method.unwrap()).uri(req.path.unwrap()).version(Version::HTTP_11);
+        for h in req.headers.iter() {
+            builder.header(h.name, h.value);
+        }
+        let request = builder.body(())
+            .map_err(|e| Error::Handshake(format!("bad http request: {}", e).into()))?;
+        src.advance(len);
+        Ok(Some(request))
+    }
+}
+
+impl Encoder for HttpCodec {
+    type Item = Response<String>;
+    type Error = Error;
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let status = item.status();
+        let head = format!("HTTP/1.1 {} {}\r\n", status.as_str(), status.canonical_reason().unwrap_or(""));
+        dst.reserve(head.len() + 2);
+        dst.put_slice(head.as_bytes());
+        for (name, value) in item.headers() {
+            dst.reserve(name.as_str().len() + value.len() + 4);
+            dst.put_slice(name.as_str().as_bytes());
+            dst.put_slice(b": ");
+            dst.put_slice(value.as_bytes());
+            dst.put_slice(b"\r\n");
+        }
+        dst.reserve(2 + item.body().len());
+        dst.put_slice(b"\r\n");
+        dst.put_slice(item.body().as_bytes());
+        Ok(())
+    }
+}
+
+enum WsSessionState<S> {
+    HandShake(Framed<S, HttpCodec>),
+    /// Flushing our response, then going on with the upgrade or giving up.
+    /// `None` when a plain HTTP request was answered instead.
+    Respond(Framed<S, HttpCodec>, Result<Option<Negotiated>, Error>),
+    Done,
+}
+
+/// Server side opening handshake on a raw stream, without hyper.
+///
+/// Resolves to the stream framed with the codec the handshake settled on.
+/// Bytes the client sent right after its request are kept for that codec.
+/// Requests that are not upgrades are answered by the built-in endpoints,
+/// resolving to `None`.
+pub struct WsSession<S> {
+    state: WsSessionState<S>,
+    config: Arc<ServerConfig>,
+    peer: Option<IpAddr>,
+    client_cert: Option<Identity>,
+}
+
+impl<S> WsSession<S> where S: AsyncRead + AsyncWrite {
+    pub fn new(s: S, config: Arc<ServerConfig>) -> Self {
+        WsSession {
+            state: WsSessionState::HandShake(Framed::from_parts(FramedParts::new(s, HttpCodec))),
+            config,
+            peer: None,
+            client_cert: None,
+        }
+    }
+
+    /// The address the stream comes from, as accepted or from a PROXY header.
+    pub fn peer(mut self, addr: SocketAddr) -> Self {
+        self.peer = Some(addr.ip());
+        self
+    }
+
+    /// Who the client certificate names, once mutual TLS verified one.
+    pub fn client_cert(mut self, identity: Identity) -> Self {
+        self.client_cert = Some(identity);
+        self
+    }
+
+    fn respond(&self, req: &Request<()>) -> (Response<String>, Result<Option<Negotiated>, Error>) {
+        if !is_upgrade(req) {
+            return (endpoint(req, &self.config), Ok(None));
+        }
+        let mut res = Response::new(String::new());
+        match ws_handshake(req, self.peer, self.client_cert.as_ref(), &self.config) {
+            Ok((headers, negotiated)) => {
+                *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+                *res.headers_mut() = headers;
+                (res, Ok(Some(negotiated)))
+            },
+            Err(e) => (refused(&e).map(|()| String::new()), Err(e)),
+        }
+    }
+}
+
+/// The response refusing an upgrade that failed with `err`.
+pub fn refused(err: &Error) -> Response<()> {
+    let mut res = Response::new(());
+    *res.status_mut() = match err {
+        Error::Auth(_) => {
+            res.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            StatusCode::UNAUTHORIZED
+        },
+        Error::Policy(_) => StatusCode::FORBIDDEN,
+        Error::NotFound(_) => StatusCode::NOT_FOUND,
+        Error::Method(_) => {
+            res.headers_mut().insert(ALLOW, HeaderValue::from_static("GET"));
+            StatusCode::METHOD_NOT_ALLOWED
+        },
+        Error::Version(_) => {
+            res.headers_mut().insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+            StatusCode::UPGRADE_REQUIRED
+        },
+        _ => StatusCode::BAD_REQUEST,
+    };
+    res.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from_static("0"));
+    res.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+    res
+}
+
+impl<S> Future for WsSession<S> where S: AsyncRead + AsyncWrite {
+    type Item = Option<(Framed<S, MessageCodec>, Negotiated)>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match mem::replace(&mut self.state, WsSessionState::Done) {
+                WsSessionState::HandShake(mut framed) => {
+                    let (res, result) = match framed.poll() {
+                        Ok(Async::Ready(Some(req))) => self.respond(&req),
+                        Ok(Async::Ready(None)) => {
+                            return Err(Error::Handshake("connection closed before the request".into()));
+                        },
+                        Ok(Async::NotReady) => {
+                            self.state = WsSessionState::HandShake(framed);
+                            return Ok(Async::NotReady);
+                        },
+                        Err(Error::Io(e)) => return Err(Error::Io(e)),
+                        // tell the client why before hanging up
+                        Err(e) => (refused(&e).map(|()| String::new()), Err(e)),
+                    };
+                    // the write buffer is empty, so this is never refused
+                    if let AsyncSink::NotReady(_) = framed.start_send(res)? {
+                        return Err(Error::Internal("handshake response refused".into()));
+                    }
+                    self.state = WsSessionState::Respond(framed, result);
+                },
+                WsSessionState::Respond(mut framed, result) => {
+                    match framed.poll_complete() {
+                        Ok(Async::Ready(())) => (),
+                        Ok(Async::NotReady) => {
+                            self.state = WsSessionState::Respond(framed, result);
+                            return Ok(Async::NotReady);
+                        },
+                        Err(e) => return Err(e),
+                    }
+                    let negotiated = match result? {
+                        Some(negotiated) => negotiated,
+                        None => return Ok(Async::Ready(None)),
+                    };
+                    let parts = framed.into_parts();
+                    let mut upgraded = FramedParts::new(parts.io, server_codec(&negotiated, &self.config));
+                    upgraded.read_buf = parts.read_buf;
+                    return Ok(Async::Ready(Some((Framed::from_parts(upgraded), negotiated))));
+                },
+                WsSessionState::Done => panic!("WsSession polled after completion"),
+            }
+        }
+    }
+}
+
+/// How we dial a WebSocket server.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Sent as the `Host` header.
+    pub host: String,
+    /// Request target, e.g. `/brg`.
+    pub path: String,
+    /// Extra headers sent with the request, e.g. for authentication.
+    pub headers: HeaderMap,
+    /// Subprotocols to offer, most preferred first.
+    pub subprotocols: Vec<String>,
+    /// Our permessage-deflate offer, or `None` to not offer it.
+    pub deflate: Option<DeflateConfig>,
+    pub limits: Limits,
+    /// Largest payload sent in a single frame, bigger messages are fragmented.
+    pub max_frame_size: Option<usize>,
+    /// Proxy to tunnel through, `None` to use `HTTPS_PROXY` or `ALL_PROXY` if
+    /// set and `NO_PROXY` does not list the host.
+    pub proxy: Option<Proxy>,
+    /// Connect with wss://, or `None` for plain ws://.
+    pub tls: Option<ClientTlsConfig>,
+}
+
+impl ClientConfig {
+    pub fn new(host: &str, path: &str) -> Self {
+        ClientConfig {
+            host: host.to_owned(),
+            path: path.to_owned(),
+            headers: HeaderMap::new(),
+            subprotocols: vec![BrgMsgFormat::Binary.subprotocol().to_owned()],
+            deflate: Some(DeflateConfig::default()),
+            limits: Limits::default(),
+            max_frame_size: None,
+            proxy: None,
+            tls: None,
+        }
+    }
+}
+
+/// What the server picked from our offers.
+pub struct Accepted {
+    pub subprotocol: Option<String>,
+    pub deflate: Option<DeflateConfig>,
+}
+
+fn ws_client_request(key: &str, config: &ClientConfig) -> Result<Request<()>, Error> {
+    let mut builder = Request::builder();
+    builder.method(Method::GET).uri(config.path.as_str()).version(Version::HTTP_11)
+        .header(HOST, config.host.as_str())
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "Upgrade")
+        .header(SEC_WEBSOCKET_KEY, key)
+        .header(SEC_WEBSOCKET_VERSION, "13");
+    if !config.subprotocols.is_empty() {
+        builder.header(SEC_WEBSOCKET_PROTOCOL, config.subprotocols.join(", ").as_str());
+    }
+    if let Some(ref deflate) = config.deflate {
+        builder.header(SEC_WEBSOCKET_EXTENSIONS, deflate.offer().as_str());
+    }
+    let mut req = builder.body(())
+        .map_err(|e| Error::Handshake(format!("bad client request: {}", e).into()))?;
+    for (name, value) in config.headers.iter() {
+        req.headers_mut().append(name, value.clone());
+    }
+    Ok(req)
+}
+
+/// Checks the server's response to our upgrade request sent with `key`.
+fn ws_verify_response(res: &Response<()>, key: &str, config: &ClientConfig) -> Result<Accepted, Error> {
+    if res.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Err(Error::Handshake(format!("unexpected response status: {}", res.status()).into()));
+    }
+    let headers = res.headers();
+    if !has_token(headers, UPGRADE, "websocket") {
+        return Err(Error::Handshake("no websocket upgrade header in response".into()));
+    }
+    if !has_token(headers, CONNECTION, "upgrade") {
+        return Err(Error::Handshake("no upgrade connection header in response".into()));
+    }
+    match headers.get(SEC_WEBSOCKET_ACCEPT) {
+        Some(v) if v == ws_gen_accept_header(key).as_str() => (),
+        Some(v) => return Err(Error::Handshake(format!("sec-websocket-accept mismatch: {:?}", v).into())),
+        None => return Err(Error::Handshake("no sec-websocket-accept header".into())),
+    }
+    let subprotocol = match headers.get(SEC_WEBSOCKET_PROTOCOL) {
+        Some(v) => match v.to_str() {
+            Ok(name) if config.subprotocols.iter().any(|p| p == name) => Some(name.to_owned()),
+            _ => return Err(Error::Handshake(format!("server picked a subprotocol we did not offer: {:?}", v).into())),
+        },
+        None => None,
+    };
+    let extensions = headers.get_all(SEC_WEBSOCKET_EXTENSIONS).iter()
+        .map(|v| v.to_str().map_err(|_| Error::Handshake("bad sec-websocket-extensions header".into())))
+        .collect::<Result<Vec<_>, _>>()?
+        .join(", ");
+    let deflate = match config.deflate {
+        _ if extensions.is_empty() => None,
+        Some(ref offer) => Some(offer.accept_response(&extensions)?),
+        None => return Err(Error::Handshake(format!("server picked extensions we did not offer: {}", extensions).into())),
+    };
+    Ok(Accepted { subprotocol, deflate })
+}
+
+/// The codec driving a client connection once the handshake is done.
+pub fn client_codec(accepted: &Accepted, config: &ClientConfig) -> MessageCodec {
+    let mut codec = MessageCodec::new(WsCodec::client().strict(true).limits(config.limits.clone()));
+    if let Some(size) = config.max_frame_size {
+        codec = codec.max_frame_size(size);
+    }
+    if let Some(ref deflate) = accepted.deflate {
+        codec = codec.deflate(deflate);
+    }
+    codec
+}
+
+/// Writes request heads and reads response heads, the client side of `HttpCodec`.
+pub struct HttpClientCodec;
+
+impl Decoder for HttpClientCodec {
+    type Item = Response<()>;
+    type Error = Error;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut res = httparse::Response::new(&mut headers);
+        let len = match res.parse(src) {
+            Ok(httparse::Status::Complete(len)) => len,
+            Ok(httparse::Status::Partial) => {
+                if src.len() > MAX_REQUEST_HEAD {
+                    return Err(Error::Capacity(format!("response head exceeds {} bytes", MAX_REQUEST_HEAD).into()));
+                }
+                return Ok(None);
+            },
+            Err(e) => return Err(Error::Handshake(format!("bad http response: {}", e).into())),
+        };
+        let mut builder = Response::builder();
+        // set once parsing is complete
+        builder.status(res.code.unwrap()).version(Version::HTTP_11);
+        for h in res.headers.iter() {
+            builder.header(h.name, h.value);
+        }
+        let response = builder.body(())
+            .map_err(|e| Error::Handshake(format!("bad http response: {}", e).into()))?;
+        src.advance(len);
+        Ok(Some(response))
+    }
+}
+
+impl Encoder for HttpClientCodec {
+    type Item = Request<()>;
+    type Error = Error;
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let head = format!("{} {} HTTP/1.1\r\n", item.method(), item.uri());
+        dst.reserve(head.len() + 2);
+        dst.put_slice(head.as_bytes());
+        for (name, value) in item.headers() {
+            dst.reserve(name.as_str().len() + value.len() + 4);
+            dst.put_slice(name.as_str().as_bytes());
+            dst.put_slice(b": ");
+            dst.put_slice(value.as_bytes());
+            dst.put_slice(b"\r\n");
+        }
+        dst.reserve(2);
+        dst.put_slice(b"\r\n");
+        Ok(())
+    }
+}
+
+enum WsClientState<S> {
+    /// Flushing our request.
+    Request(Framed<S, HttpClientCodec>),
+    Response(Framed<S, HttpClientCodec>),
+    Done,
+}
+
+/// Client side opening handshake on a connected stream.
+///
+/// Resolves to the stream framed with a client codec. Bytes the server sent
+/// right after its response are kept for that codec.
+pub struct WsClient<S> {
+    state: WsClientState<S>,
+    config: ClientConfig,
+    key: String,
+}
+
+impl<S> WsClient<S> where S: AsyncRead + AsyncWrite {
+    pub fn new(s: S, config: ClientConfig) -> Result<Self, Error> {
+        let key = base64::encode(&rand::random::<[u8; 16]>());
+        let req = ws_client_request(&key, &config)?;
+        let mut framed = Framed::from_parts(FramedParts::new(s, HttpClientCodec));
+        // the write buffer is empty, so this only encodes the request
+        if let AsyncSink::NotReady(_) = framed.start_send(req)? {
+            return Err(Error::Internal("handshake request refused".into()));
+        }
+        Ok(WsClient {
+            state: WsClientState::Request(framed),
+            config,
+            key,
+        })
+    }
+}
+
+impl<S> Future for WsClient<S> where S: AsyncRead + AsyncWrite {
+    type Item = (Framed<S, MessageCodec>, Accepted);
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match mem::replace(&mut self.state, WsClientState::Done) {
+                WsClientState::Request(mut framed) => {
+                    if let Async::NotReady = framed.poll_complete()? {
+                        self.state = WsClientState::Request(framed);
+                        return Ok(Async::NotReady);
+                    }
+                    self.state = WsClientState::Response(framed);
+                },
+                WsClientState::Response(mut framed) => {
+                    let res = match framed.poll()? {
+                        Async::Ready(Some(res)) => res,
+                        Async::Ready(None) => {
+                            return Err(Error::Handshake("connection closed before the response".into()));
+                        },
+                        Async::NotReady => {
+                            self.state = WsClientState::Response(framed);
+                            return Ok(Async::NotReady);
+                        },
+                    };
+                    let accepted = ws_verify_response(&res, &self.key, &self.config)?;
+                    let parts = framed.into_parts();
+                    let mut upgraded = FramedParts::new(parts.io, client_codec(&accepted, &self.config));
+                    upgraded.read_buf = parts.read_buf;
+                    return Ok(Async::Ready((Framed::from_parts(upgraded), accepted)));
+                },
+                WsClientState::Done => panic!("WsClient polled after completion"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::{self, Cursor, Read, Write};
+    use std::rc::Rc;
+    use crate::wsproto::Message;
+
+    /// Reads what is given in `input`, blocking once it runs out, and records
+    /// everything written.
+    pub(crate) struct MockStream {
+        pub(crate) input: Rc<RefCell<Cursor<Vec<u8>>>>,
+        pub(crate) output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl MockStream {
+        pub(crate) fn new(input: &[u8]) -> Self {
+            MockStream {
+                input: Rc::new(RefCell::new(Cursor::new(input.to_vec()))),
+                output: Rc::new(RefCell::new(Vec::new())),
+            }
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.input.borrow_mut().read(buf)? {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                n => Ok(n),
+            }
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for MockStream {}
+
+    impl AsyncWrite for MockStream {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    type Upgraded = Option<(Framed<MockStream, MessageCodec>, Negotiated)>;
+
+    fn handshake(request: &[u8]) -> (Result<Upgraded, Error>, String) {
+        let stream = MockStream::new(request);
+        let output = stream.output.clone();
+        let result = match WsSession::new(stream, Arc::new(ServerConfig::default())).poll() {
+            Ok(Async::Ready(upgraded)) => Ok(upgraded),
+            Ok(Async::NotReady) => panic!("handshake did not finish"),
+            Err(e) => Err(e),
+        };
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        (result, output)
+    }
+
+    const REQUEST: &[u8] = b"GET /brg HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Protocol: chat, wsbrg.v1.base64\r\n\
+        \r\n";
+
+    #[test]
+    fn test_accept_header() {
+        // the example from RFC 6455 section 1.3
+        assert_eq!(ws_gen_accept_header("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_native_handshake() {
+        let mut request = REQUEST.to_vec();
+        // a masked "hi" text frame sent right behind the request
+        request.extend_from_slice(b"\x81\x82\x00\x00\x00\x00hi");
+        let (result, output) = handshake(&request);
+        let (mut framed, negotiated) = result.unwrap().unwrap();
+        assert!(output.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(output.contains("sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(output.contains("sec-websocket-protocol: wsbrg.v1.base64\r\n"));
+        assert!(output.ends_with("\r\n\r\n"));
+        assert_eq!(negotiated.format, BrgMsgFormat::Base64);
+        assert!(negotiated.deflate.is_none());
+        match framed.poll() {
+            Ok(Async::Ready(Some(msg))) => assert_eq!(msg, Message::Text(BytesMut::from("hi"))),
+            _ => panic!("leftover frame lost"),
+        }
+    }
+
+    #[test]
+    fn test_native_handshake_rejected() {
+        let request = String::from_utf8(REQUEST.to_vec()).unwrap()
+            .replace("Sec-WebSocket-Version: 13", "Sec-WebSocket-Version: 8");
+        let (result, output) = handshake(request.as_bytes());
+        match result {
+            Err(Error::Version(_)) => (),
+            _ => panic!("expected a version error"),
+        }
+        assert!(output.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(output.contains("sec-websocket-version: 13\r\n"));
+        let request = String::from_utf8(REQUEST.to_vec()).unwrap()
+            .replace("Sec-WebSocket-Key", "Sec-WebSocket-Nonce");
+        let (result, output) = handshake(request.as_bytes());
+        match result {
+            Err(Error::Handshake(_)) => (),
+            _ => panic!("expected a handshake error"),
+        }
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        let (result, output) = handshake(b"GET / HTTP/1.0\r\n\r\n");
+        assert!(result.is_err());
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn test_native_handshake_method() {
+        let request = String::from_utf8(REQUEST.to_vec()).unwrap().replace("GET /brg", "POST /brg");
+        let (result, output) = handshake(request.as_bytes());
+        match result {
+            Err(Error::Method(_)) => (),
+            _ => panic!("expected a method error"),
+        }
+        assert!(output.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(output.contains("allow: GET\r\n"));
+    }
+
+    #[test]
+    fn test_native_handshake_connection() {
+        let without = String::from_utf8(REQUEST.to_vec()).unwrap().replace("Connection: Upgrade\r\n", "");
+        let (result, output) = handshake(without.as_bytes());
+        match result {
+            Err(Error::Handshake(_)) => (),
+            _ => panic!("expected a handshake error"),
+        }
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        let other = String::from_utf8(REQUEST.to_vec()).unwrap().replace("Connection: Upgrade", "Connection: close");
+        assert!(handshake(other.as_bytes()).0.is_err());
+        // a list of tokens, in any case
+        let listed = String::from_utf8(REQUEST.to_vec()).unwrap().replace("Connection: Upgrade", "Connection: keep-alive, upgrade");
+        assert!(handshake(listed.as_bytes()).0.is_ok());
+    }
+
+    #[test]
+    fn test_routes() {
+        use crate::config::Route;
+        let mut dns = Route::new("/dns", "127.0.0.1:53".parse().unwrap());
+        dns.subprotocols = Some(vec![BrgMsgFormat::Binary]);
+        let game = Route::new("/game", "10.0.0.5:27015".parse().unwrap());
+        let config = Arc::new(ServerConfig { routes: vec![dns, game], ..ServerConfig::default() });
+        let upgrade = |path: &str| {
+            let request = String::from_utf8(REQUEST.to_vec()).unwrap()
+                .replace("GET /brg", &format!("GET {}", path));
+            let stream = MockStream::new(request.as_bytes());
+            let output = stream.output.clone();
+            let result = WsSession::new(stream, config.clone()).poll();
+            let output = String::from_utf8(output.borrow().clone()).unwrap();
+            (result, output)
+        };
+
+        let (result, output) = upgrade("/brg");
+        match result {
+            Err(Error::NotFound(_)) => (),
+            _ => panic!("expected no route"),
+        }
+        assert!(output.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        // the request only offers base64, which /dns does not take
+        let (result, output) = upgrade("/dns");
+        assert!(result.is_err());
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        match upgrade("/game").0 {
+            Ok(Async::Ready(Some((_, negotiated)))) => {
+                assert_eq!(negotiated.route.unwrap().target, "10.0.0.5:27015".parse().unwrap());
+            },
+            _ => panic!("expected the upgrade to go through"),
+        }
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let config = Arc::new(ServerConfig {
+            trusted_proxies: vec!["127.0.0.0/8".parse().unwrap()],
+            ..ServerConfig::default()
+        });
+        let request = String::from_utf8(REQUEST.to_vec()).unwrap()
+            .replace("Host: localhost\r\n", "Host: localhost\r\nX-Forwarded-For: 203.0.113.7\r\n");
+        let client_addr = |peer: &str| {
+            let session = WsSession::new(MockStream::new(request.as_bytes()), config.clone())
+                .peer(peer.parse().unwrap());
+            match session.wait() {
+                Ok(Some((_, negotiated))) => negotiated.client_addr.unwrap().to_string(),
+                _ => panic!("expected the upgrade to go through"),
+            }
+        };
+        assert_eq!(client_addr("127.0.0.1:40000"), "203.0.113.7");
+        assert_eq!(client_addr("198.51.100.1:40000"), "198.51.100.1");
+    }
+
+    #[test]
+    fn test_native_endpoint() {
+        let (result, output) = handshake(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(result.unwrap().is_none());
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("\r\n\r\nok\n"));
+    }
+
+    fn client_handshake(config: ClientConfig, response: impl Fn(&str) -> Vec<u8>) -> Result<(Framed<MockStream, MessageCodec>, Accepted), Error> {
+        let stream = MockStream::new(b"");
+        let (input, output) = (stream.input.clone(), stream.output.clone());
+        let mut client = WsClient::new(stream, config).unwrap();
+        // the request goes out, then the client waits for the response
+        assert!(client.poll().unwrap().is_not_ready());
+        let request = String::from_utf8(output.borrow().clone()).unwrap();
+        assert!(request.starts_with("GET /brg HTTP/1.1\r\n"));
+        assert!(request.contains("host: example.com\r\n"));
+        let key = request.lines()
+            .find(|l| l.starts_with("sec-websocket-key: "))
+            .map(|l| l["sec-websocket-key: ".len()..].to_owned())
+            .unwrap();
+        *input.borrow_mut() = Cursor::new(response(&ws_gen_accept_header(&key)));
+        match client.poll()? {
+            Async::Ready(upgraded) => Ok(upgraded),
+            Async::NotReady => panic!("client handshake did not finish"),
+        }
+    }
+
+    #[test]
+    fn test_client_handshake() {
+        let mut config = ClientConfig::new("example.com", "/brg");
+        config.headers.insert("authorization", HeaderValue::from_static("Bearer t0ken"));
+        let (mut framed, accepted) = client_handshake(config.clone(), |accept| {
+            // an unmasked "hi" text frame right behind the response
+            format!("HTTP/1.1 101 Switching Protocols\r\n\
+                Upgrade: websocket\r\n\
+                Connection: Upgrade\r\n\
+                Sec-WebSocket-Accept: {}\r\n\
+                Sec-WebSocket-Protocol: wsbrg.v1\r\n\
+                Sec-WebSocket-Extensions: permessage-deflate\r\n\
+                \r\n", accept).into_bytes().into_iter().chain(b"\x81\x02hi".iter().cloned()).collect()
+        }).unwrap();
+        assert_eq!(accepted.subprotocol.as_deref(), Some("wsbrg.v1"));
+        assert!(accepted.deflate.is_some());
+        match framed.poll() {
+            Ok(Async::Ready(Some(msg))) => assert_eq!(msg, Message::Text(BytesMut::from("hi"))),
+            _ => panic!("leftover frame lost"),
+        }
+    }
+
+    #[test]
+    fn test_client_handshake_rejected() {
+        let config = ClientConfig::new("example.com", "/brg");
+        let response = |status: &str, accept: &str, extra: &str| format!(
+            "HTTP/1.1 {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n{}\r\n",
+            status, accept, extra
+        ).into_bytes();
+        let rejected = |f: &dyn Fn(&str) -> Vec<u8>| {
+            matches!(client_handshake(config.clone(), f), Err(Error::Handshake(_)))
+        };
+        assert!(rejected(&|accept| response("400 Bad Request", accept, "")));
+        assert!(rejected(&|_| response("101 Switching Protocols", "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", "")));
+        assert!(rejected(&|accept| response("101 Switching Protocols", accept, "Sec-WebSocket-Protocol: chat\r\n")));
+        assert!(!rejected(&|accept| response("101 Switching Protocols", accept, "")));
+    }
+
+    #[test]
+    fn test_origin_matches() {
+        assert!(origin_matches("https://example.com", "https://Example.com"));
+        assert!(!origin_matches("https://example.com", "http://example.com"));
+        assert!(origin_matches("https://*.example.com", "https://a.example.com"));
+        assert!(origin_matches("https://*.example.com", "https://a.b.example.com"));
+        assert!(!origin_matches("https://*.example.com", "https://example.com"));
+        assert!(!origin_matches("https://*.example.com", "https://evil-example.com"));
+        assert!(!origin_matches("https://*.example.com", "https://a.example.com:8443"));
+        assert!(!origin_matches("https://*.example.com", "http://a.example.com"));
+    }
+
+    #[test]
+    fn test_origin_policy() {
+        let policy = OriginPolicy {
+            allowed: AllowedOrigins::Only(vec!["https://*.example.com".to_owned()]),
+            allow_missing: false,
+        };
+        assert!(policy.check(Some("https://app.example.com")).is_ok());
+        assert!(policy.check(Some("https://evil.com")).is_err());
+        assert!(policy.check(None).is_err());
+        assert!(OriginPolicy::default().check(None).is_ok());
+        assert!(OriginPolicy::default().check(Some("https://evil.com")).is_ok());
+    }
+
+    #[test]
+    fn test_origin_forbidden() {
+        let mut config = ServerConfig::default();
+        config.origins.allowed = AllowedOrigins::Only(vec!["https://example.com".to_owned()]);
+        let request = String::from_utf8(REQUEST.to_vec()).unwrap()
+            .replace("Host: localhost\r\n", "Host: localhost\r\nOrigin: https://evil.com\r\n");
+        let stream = MockStream::new(request.as_bytes());
+        let output = stream.output.clone();
+        match WsSession::new(stream, Arc::new(config)).poll() {
+            Err(Error::Policy(details)) => assert!(details.contains("https://evil.com")),
+            _ => panic!("expected the origin to be refused"),
+        }
+        assert!(output.borrow().starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+    }
+
+    #[test]
+    fn test_unauthorized() {
+        use crate::auth::BearerTokens;
+        let mut config = ServerConfig::default();
+        config.auth.push(Arc::new(BearerTokens {
+            tokens: vec![("s3cret".to_owned(), Identity("alice".to_owned()))],
+        }));
+        let config = Arc::new(config);
+
+        let stream = MockStream::new(REQUEST);
+        let output = stream.output.clone();
+        match WsSession::new(stream, config.clone()).poll() {
+            Err(Error::Auth(_)) => (),
+            _ => panic!("expected the upgrade to be refused"),
+        }
+        let output = String::from_utf8(output.borrow().clone()).unwrap();
+        assert!(output.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(output.contains("www-authenticate: Bearer\r\n"));
+
+        let request = String::from_utf8(REQUEST.to_vec()).unwrap()
+            .replace("Host: localhost\r\n", "Host: localhost\r\nAuthorization: Bearer s3cret\r\n");
+        match WsSession::new(MockStream::new(request.as_bytes()), config.clone()).poll() {
+            Ok(Async::Ready(Some((_, negotiated)))) => {
+                assert_eq!(negotiated.identity, Some(Identity("alice".to_owned())))
+            },
+            _ => panic!("expected the upgrade to go through"),
+        }
+
+        // a verified client certificate needs no token, and names the client
+        // even when one is sent
+        let tunnel = Identity("tunnel-a.example".to_owned());
+        for request in &[REQUEST, request.as_bytes()] {
+            match WsSession::new(MockStream::new(request), config.clone()).client_cert(tunnel.clone()).poll() {
+                Ok(Async::Ready(Some((_, negotiated)))) => assert_eq!(negotiated.identity, Some(tunnel.clone())),
+                _ => panic!("expected the upgrade to go through"),
+            }
+        }
+    }
+}
@@ -1,21 +1,21 @@
 extern crate bytes;
 extern crate tokio_tcp;
-extern crate ws;
-
-use futures::sink::Sink;
-use futures::stream::Stream;
-use futures::Future;
-use std::net::SocketAddr;
-use tokio::codec::{Decoder, Framed, FramedRead, LinesCodec};
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpListener;
 
 mod wsproto;
-// mod test;
+mod auth;
+mod config;
+mod endpoints;
+mod forwarded;
+mod handshake;
+mod proxy;
+mod proxy_protocol;
+mod test;
+mod tls;
 // mod brg_session;
 mod test_udp;
 
-//mod ws_msg;
+mod ws_msg;
+mod x509;
 //mod some_codecs;
 
 //use ws_msg::*;
@@ -36,98 +36,18 @@ mod test_udp;
 //        .map_err(|e| {eprintln!("accept error {:?}", e)});
 //    tokio::run(f);
 //}
-//
-//extern crate httparse;
-//
-//use httparse::{Request, Result};
-//use bytes::BytesMut;
-//use tokio_tcp::TcpStream;
-//
-//#[derive(Debug)]
-//struct ParseError(string);
-//
-//impl From<std::io::Error> for ParseError {
-//    fn from(err: std::io::Error) -> Self {
-//        ParseError(format!("IO ERROR: {:?}", err))
-//    }
-//}
-//
-//struct HttpReqParser;
-//
-//impl HttpReqParser {
-//    fn new() -> Self {
-//        HttpReqParser
-//    }
-//}
-//
-//impl Decoder for HttpReqParser {
-//    type Item = Request;
-//    type Error = ParseError;
-//    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-//        let mut headers = [httparse::EMPTY_HEADER; 16];
-//        let buf = src.as_byte_slice_mut();
-//        let mut req = Request::new(&mut headers);
-//        match self.req.parse(buf)? {
-//            Status::Complete(s) => {
-//                src.split_at(s);
-//                Ok(Some(self.req))
-//            },
-//            Status::Partial => Ok(None)
-//        }
-//    }
-//}
-//
-//use wsproto::WsCodec;
-//
-//enum WsSessionState<S> where S: AsyncRead + AsyncWrite {
-//    HandShake(FramedRead<S, HttpReqParser>),
-//    Work(Framed<S, WsCodec>),
-//}
-//
-//struct WsSession<S> {
-//    state: WsSessionState,
-//}
-//
-//impl<S> WsSession<S> {
-//    fn new(s: S) {
-//        WsSession {
-//            state: WsSessionState::HandShake(FramedRead::new(s, HttpReqParser)),
-//        }
-//    }
-//}
-//
-//impl<S> Future for WsSession<S> {
-//    type Item = ();
-//    type Error = ParseError;
-//    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-//        match self.state {
-//            WsSessionState::HandShake(f) => {
-//                self.state = f.
-//            }
-//        }
-//    }
-//}
-//
-//fn run() {
-//    let addr = "0.0.0.0:8888".parse::<SocketAddr>().unwrap();
-//    let listener = TcpListener::bind(&addr).unwrap();
-//    tokio::run(listener.incoming().for_each(|socket| {
-//        let (reader, writer) = socket.split();
-//        let http_reader = FramedRead::new(reader, HttpReqParser::new());
-//        tokio::spawn(http_reader.for_each(move |i| {
-//
-//        }).then(move |res| {
-//            match res {
-//                Ok(_) => Ok(()),
-//                Err(_) => Err(()),
-//            }
-//        }))
-//    }))
-//}
 
 fn main() {
     //    run();
     // println!("Hello world!!!");
-    // test::test();
-    test_udp::test();
+    match std::env::args().nth(1).as_deref() {
+        Some("ws") => test::test(),
+        Some("ws-native") => test::serve_native(config::ServerConfig::default()),
+        Some("ws-client") => test::client(
+            handshake::ClientConfig::new("localhost:8080", "/"),
+            "127.0.0.1:5300".parse().unwrap(),
+            "127.0.0.1:5301".parse().unwrap(),
+        ),
+        _ => test_udp::test(),
+    }
 }
@@ -0,0 +1,210 @@
+//! The HAProxy PROXY protocol, versions 1 and 2, with which a load balancer
+//! passes on the client address ahead of the connection's own bytes.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use futures::{Async, Future, Poll};
+use tokio::io::AsyncRead;
+
+use crate::wsproto::Error;
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest version 1 header, with both addresses IPv6.
+const V1_MAX: usize = 107;
+
+#[derive(Clone, Copy)]
+enum Step {
+    /// The first 12 bytes, enough to tell the versions apart.
+    Signature,
+    V1Line,
+    V2Header,
+    V2Addresses(usize),
+}
+
+/// Reads a PROXY protocol header off a freshly accepted stream, resolving to
+/// the stream and the client address it carries.
+///
+/// The address is `None` for health checks the balancer makes itself and for
+/// protocols it does not describe. The header is read an exact length or a
+/// byte at a time, so the request behind it is left in the stream.
+pub struct ProxyHeader<S> {
+    stream: Option<S>,
+    step: Step,
+    buf: Vec<u8>,
+}
+
+impl<S> ProxyHeader<S> where S: AsyncRead {
+    pub fn new(s: S) -> Self {
+        ProxyHeader { stream: Some(s), step: Step::Signature, buf: Vec::new() }
+    }
+
+    fn wanted(&self) -> Option<usize> {
+        match self.step {
+            Step::Signature => Some(12),
+            Step::V1Line => None,
+            Step::V2Header => Some(16),
+            Step::V2Addresses(len) => Some(16 + len),
+        }
+    }
+
+    fn complete(&self) -> bool {
+        match self.wanted() {
+            Some(n) => self.buf.len() >= n,
+            None => self.buf.ends_with(b"\r\n"),
+        }
+    }
+
+    /// Handles the bytes read so far, returning the address once the header is done.
+    fn advance(&mut self) -> Result<Option<Option<SocketAddr>>, Error> {
+        match self.step {
+            Step::Signature if self.buf == V2_SIGNATURE => self.step = Step::V2Header,
+            Step::Signature if self.buf.starts_with(b"PROXY ") => self.step = Step::V1Line,
+            Step::Signature => return Err(Error::Handshake("no proxy protocol header".into())),
+            Step::V1Line => return parse_v1(&self.buf).map(Some),
+            Step::V2Header => {
+                if self.buf[12] >> 4 != 2 {
+                    return Err(Error::Handshake("unsupported proxy protocol version".into()));
+                }
+                let len = u16::from_be_bytes([self.buf[14], self.buf[15]]) as usize;
+                self.step = Step::V2Addresses(len);
+            },
+            Step::V2Addresses(_) => return parse_v2(&self.buf).map(Some),
+        }
+        Ok(None)
+    }
+
+    fn drive(&mut self, stream: &mut S) -> Poll<Option<SocketAddr>, Error> {
+        loop {
+            while !self.complete() {
+                let mut byte = [0u8; 64];
+                // read no further than the header
+                let want = self.wanted().map_or(1, |n| (n - self.buf.len()).min(byte.len()));
+                match stream.poll_read(&mut byte[..want])? {
+                    Async::Ready(0) => return Err(Error::Handshake("connection closed in proxy protocol header".into())),
+                    Async::Ready(n) => self.buf.extend_from_slice(&byte[..n]),
+                    Async::NotReady => return Ok(Async::NotReady),
+                }
+                if let Step::V1Line = self.step {
+                    if self.buf.len() > V1_MAX {
+                        return Err(Error::Handshake("proxy protocol header too long".into()));
+                    }
+                }
+            }
+            if let Some(addr) = self.advance()? {
+                return Ok(Async::Ready(addr));
+            }
+        }
+    }
+}
+
+impl<S> Future for ProxyHeader<S> where S: AsyncRead {
+    type Item = (S, Option<SocketAddr>);
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut stream = self.stream.take().expect("ProxyHeader polled after completion");
+        match self.drive(&mut stream) {
+            Ok(Async::Ready(addr)) => Ok(Async::Ready((stream, addr))),
+            result => {
+                self.stream = Some(stream);
+                result.map(|_| Async::NotReady)
+            },
+        }
+    }
+}
+
+/// `PROXY TCP4 <src> <dst> <src port> <dst port>\r\n`, or `PROXY UNKNOWN ...`.
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    let bad = || Error::Handshake("bad proxy protocol v1 header".into());
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| bad())?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => (),
+        _ => return Err(bad()),
+    }
+    let ip = fields[2].parse::<IpAddr>().map_err(|_| bad())?;
+    let port = fields[4].parse::<u16>().map_err(|_| bad())?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// The binary header: signature, version and command, family, length, addresses.
+fn parse_v2(header: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    let bad = || Error::Handshake("bad proxy protocol v2 header".into());
+    match header[12] & 0x0f {
+        // LOCAL, the balancer's own connection
+        0 => return Ok(None),
+        1 => (),
+        _ => return Err(bad()),
+    }
+    let addrs = &header[16..];
+    let port = |at: usize| u16::from_be_bytes([addrs[at], addrs[at + 1]]);
+    match header[13] {
+        // TCP or UDP over IPv4
+        0x11 | 0x12 if addrs.len() >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            Ok(Some(SocketAddr::new(ip.into(), port(8))))
+        },
+        // TCP or UDP over IPv6
+        0x21 | 0x22 if addrs.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port(32))))
+        },
+        0x11 | 0x12 | 0x21 | 0x22 => Err(bad()),
+        // unix sockets and unspecified
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::tests::MockStream;
+
+    fn read_header(input: &[u8]) -> (Result<Option<SocketAddr>, Error>, u64) {
+        match ProxyHeader::new(MockStream::new(input)).poll() {
+            Ok(Async::Ready((stream, addr))) => {
+                let position = stream.input.borrow().position();
+                (Ok(addr), position)
+            },
+            Ok(Async::NotReady) => panic!("header not read"),
+            Err(e) => (Err(e), 0),
+        }
+    }
+
+    #[test]
+    fn test_v1() {
+        let header = b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 8080\r\n";
+        let mut input = header.to_vec();
+        input.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let (addr, position) = read_header(&input);
+        assert_eq!(addr.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(position, header.len() as u64);
+
+        let (addr, _) = read_header(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n");
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+        assert_eq!(read_header(b"PROXY UNKNOWN\r\n").0.unwrap(), None);
+        assert!(read_header(b"PROXY TCP4 nonsense\r\n").0.is_err());
+        assert!(read_header(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").0.is_err());
+    }
+
+    #[test]
+    fn test_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        // PROXY over TCP4, 12 bytes of addresses and a 3 byte TLV
+        header.extend_from_slice(&[0x21, 0x11, 0, 15]);
+        header.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 0x1f, 0x90]);
+        header.extend_from_slice(&[0x04, 0, 0]);
+        let mut input = header.clone();
+        input.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let (addr, position) = read_header(&input);
+        assert_eq!(addr.unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(position, header.len() as u64);
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&local).0.unwrap(), None);
+        local[12] = 0x11;
+        assert!(read_header(&local).0.is_err());
+    }
+}
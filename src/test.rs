@@ -1,145 +1,101 @@
 extern crate hyper;
 extern crate tokio;
 extern crate tokio_tcp;
-extern crate base64;
-extern crate crypto;
 
-use std::borrow::Borrow;
-use std::net::SocketAddr;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
-use futures::{Future, Stream};
-use futures::sink::{Sink};
-use http::{HeaderValue, StatusCode};
-use http::header::{UPGRADE, ORIGIN, SEC_WEBSOCKET_VERSION, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_ACCEPT, CONNECTION};
-use httparse::Error::HeaderName;
-use hyper::{Body, Request, Response, Server};
-use hyper::server::Builder;
-use hyper::upgrade::{OnUpgrade, Parts, Upgraded};
-use tokio::net::TcpListener;
-use tokio_codec::Framed;
+use bytes::Bytes;
+use futures::{Future, Sink, Stream};
+use futures::future::{self, Either};
+use futures::sync::mpsc;
+use http::StatusCode;
+use hyper::{Body, Request, Response};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UdpFramed, UdpSocket};
+use tokio::prelude::FutureExt;
+use tokio_codec::{BytesCodec, Framed};
+use tokio_rustls::TlsAcceptor;
 
-use crate::wsproto::{WsCodec, Frame, OpCode};
+use crate::auth::Identity;
+use crate::config::ServerConfig;
+use crate::endpoints::{endpoint, is_upgrade};
+use crate::handshake::{ws_handshake, refused, server_codec, ClientConfig, Negotiated, WsSession};
+use crate::proxy::ws_connect;
+use crate::proxy_protocol::ProxyHeader;
+use crate::tls::{MaybeTls, ServerStream, ServerTls};
+use crate::wsproto::{Message, MessageCodec, CloseCode, Connection, Error, Handler, Outbox};
+use crate::ws_msg::{BrgMsg, BrgMsgFormat};
 
 use self::hyper::server::conn::Http;
-use self::hyper::service::{service_fn_ok, service_fn, make_service_fn};
-use self::crypto::digest::Digest;
-
-fn process_ws_frame(mut msg: Frame) -> Option<Frame> {
-    println!("Received message: {:?}", msg.opcode);
-    // process mask if necessary
-    msg.apply_mask();
-    // print msg
-    println!("Message: {:?}", msg.payload);
-    // respond to ping
-    if msg.opcode == OpCode::Text && msg.payload == "echo" {
-        println!("Debug echo");
-        return Some(Frame::text_bytes_mut(msg.payload))
-    }
-    if msg.opcode == OpCode::Ping {
-        Some(Frame::pong())
-    } else {
-        None
-    }
-}
+use self::hyper::service::service_fn_ok;
 
-fn ws_gen_accept_header(v: &str) -> String {
-    let s = format!("{}{}", v, "258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
-    let mut sha1 = crypto::sha1::Sha1::new();
-    sha1.input_str(s.as_str());
-    let mut sha1_bytes = [0u8; 20];
-    sha1.result(&mut sha1_bytes);
-    base64::encode(&sha1_bytes)
+/// Datagrams waiting to be sent on over UDP or WebSocket.
+const BRIDGE_QUEUE: usize = 64;
+/// How long a new connection may take to send its PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Speaks `BrgMsg` in the negotiated format. `SendData` is bridged over UDP,
+/// to the matched route's target on the server and to the local application
+/// on the client, or echoed back when the server has no routes. Everything
+/// else is only logged.
+struct BrgHandler {
+    format: BrgMsgFormat,
+    identity: Option<Identity>,
+    /// Datagrams for the UDP end, see `bridge`.
+    bridge: Option<mpsc::Sender<Bytes>>,
+    client_addr: Option<IpAddr>,
 }
 
-enum HandshakeError {
-    InvalidWSRequest,
+impl BrgHandler {
+    /// Who is on the other end, for log lines.
+    fn peer(&self) -> String {
+        let user = self.identity.as_ref().map_or("anonymous", |id| id.0.as_str());
+        match self.client_addr {
+            Some(addr) => format!("{}@{}", user, addr),
+            None => user.to_owned(),
+        }
+    }
 }
 
-fn ws_handshake(req: &Request<Body>) -> Result<Response<Body>, HandshakeError> {
-    let mut res = Response::new(Body::empty());
-    // reject non websocket requests
-    let mut is_valid = true;
-    is_valid = is_valid && match req.headers().get(UPGRADE) {
-        Some(v) => {
-            if v == "websocket" {
-                true
-            } else {
-                eprintln!("invalid upgrade header value: {}", v.to_str().unwrap());
-                false
-            }
-        },
-        None => {
-            eprintln!("no upgrade header");
-            false
-        },
-    };
-    // this is for browser only
-    // req.headers().contains_key(ORIGIN) &&
-    is_valid = is_valid && match req.headers().get(SEC_WEBSOCKET_VERSION) {
-        Some(v) => {
-            if v == "13" {
-                true
-            } else {
-                eprintln!("invalid sec-websocket-version: {}", v.to_str().unwrap());
-                false
-            }
-        },
-        None => {
-            eprintln!("no sec-websocket-version header");
-            false
-        },
-    };
-    is_valid = is_valid && match req.headers().get(SEC_WEBSOCKET_KEY) {
-        Some(v) => {
-            match base64::decode(v.as_bytes()) {
-                Ok(k) => {
-                    if k.len() == 16 {
-                        // Sec-WebSocket-Accept
-                        res.headers_mut().insert(
-                            SEC_WEBSOCKET_ACCEPT,
-                            HeaderValue::from_str(
-                                ws_gen_accept_header(v.to_str().unwrap()).as_str()
-                            ).unwrap()
-                        );
-                        true
-                    } else {
-                        eprintln!("base64 decoded sec-websocket-key length mismatch: {}", k.len());
-                        false
-                    }
-                },
-                Err(e) => {
-                    eprintln!("base64 decode sec-websocket-key error: {:?}", e);
-                    false
-                },
+impl Handler for BrgHandler {
+    fn on_message(&mut self, out: &mut Outbox, msg: Message) -> Result<(), Error> {
+        let msg = self.format.decode(msg)
+            .map_err(|e| Error::Policy(format!("bad bridge message: {:?}", e).into()))?;
+        // the bridge has no sessions to set up yet, only data to pass on
+        if let BrgMsg::SendData(data) = msg {
+            match self.bridge {
+                // a full queue drops the datagram, as a busy network would
+                Some(ref mut bridge) => { let _ = bridge.try_send(data); },
+                None => out.send(self.format.encode(&BrgMsg::SendData(data))),
             }
-        },
-        None => {
-            eprintln!("no sec-websocket-key header");
-            false
-        },
-    };
-    if is_valid {
-        //    *sec-websocket-protocol,
-        //    *sec-websocket-extension,
-        *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
-        res.headers_mut().insert(UPGRADE, HeaderValue::from_static("websocket"));
-        res.headers_mut().insert(CONNECTION, HeaderValue::from_static("Upgrade"));
-        Ok(res)
-    } else {
-        Err(HandshakeError::InvalidWSRequest)
+        }
+        Ok(())
+    }
+
+    fn on_error(&mut self, err: &Error) {
+        eprintln!("Connection error [{}]: {:?}", self.peer(), err);
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        println!("Connection closed [{}]: {:?} {}", self.peer(), code, reason);
     }
 }
 
-fn ws_upgrade(req: Request<Body>) -> Response<Body> {
-    // debug
-    println!("All headers:");
-    for (h, v) in req.headers().iter() {
-        println!("{} => {}", h.as_str(), v.to_str().unwrap());
+fn ws_upgrade(req: Request<Body>, peer: Option<IpAddr>, client_cert: Option<&Identity>, config: &ServerConfig) -> Response<Body> {
+    if !is_upgrade(&req) {
+        return endpoint(&req, config).map(Body::from);
     }
-    println!("End all headers");
     println!("Handling HTTP/WS Request...");
-    match ws_handshake(&req) {
-        Ok(res) => {
+    match ws_handshake(&req, peer, client_cert, config) {
+        Ok((headers, negotiated)) => {
+            let mut res = Response::new(Body::empty());
+            *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+            *res.headers_mut() = headers;
+            let config = config.clone();
             tokio::spawn(
                 req.into_body().on_upgrade().then(move |r| {
                 match r {
@@ -147,7 +103,8 @@ fn ws_upgrade(req: Request<Body>) -> Response<Body> {
                         println!("HTTP Upgraded");
                     //    let (sink, reader) = Framed::new(upgraded, WsCodec::new()).split();
                     //    tokio::spawn(sink.send_all(reader.filter_map(process_ws_frame)).then(|_| Ok(()) ));
-                        process_upgraded(upgraded);
+                        let framed = Framed::new(upgraded, server_codec(&negotiated, &config));
+                        process_upgraded(framed, negotiated, &config);
                         Ok(())
                     },
                     Err(_) => Err(()),
@@ -155,30 +112,63 @@ fn ws_upgrade(req: Request<Body>) -> Response<Body> {
             }));
             res
         },
-        Err(_) => {
-            eprintln!("Invalid request");
-            let mut res = Response::new(Body::empty());
-            *res.status_mut() = StatusCode::BAD_REQUEST;
-            res
+        Err(e) => {
+            eprintln!("Invalid request: {}", e);
+            refused(&e).map(|()| Body::empty())
         }
     }
 
 }
 
-use futures::sync::mpsc;
+fn process_upgraded<S>(framed: Framed<S, MessageCodec>, negotiated: Negotiated, config: &ServerConfig)
+    where S: AsyncRead + AsyncWrite + Send + 'static {
+    let socket = match negotiated.route {
+        Some(ref route) => match bridge_socket(route.target) {
+            Ok(socket) => Some((socket, route.target, mpsc::channel(BRIDGE_QUEUE))),
+            Err(e) => {
+                eprintln!("UDP bridge to {} failed: {}", route.target, e);
+                return;
+            },
+        },
+        None => None,
+    };
+    let handler = BrgHandler {
+        format: negotiated.format,
+        identity: negotiated.identity,
+        bridge: socket.as_ref().map(|(_, _, (tx, _))| tx.clone()),
+        client_addr: negotiated.client_addr,
+    };
+    let mut conn = Connection::new(framed, handler).max_queued(config.max_queued);
+    if let Some(ref keepalive) = config.keepalive {
+        conn = conn.keepalive(keepalive.clone());
+    }
+    if let Some((socket, target, (_, from_peer))) = socket {
+        bridge(socket, target, negotiated.format, from_peer, conn.sender(BRIDGE_QUEUE));
+    }
+    my_spawn(conn);
+}
 
-fn process_upgraded(upgraded: Upgraded) {
-    let (sink, reader) = Framed::new(upgraded, WsCodec::new()).split();
-    let (sink_sender, sink_receiver) = mpsc::channel::<Frame>(64);
-    my_spawn(reader.for_each(move |msg| {
-        let sink_sender = (&sink_sender).clone();
-        if let Some(f) = process_ws_frame(msg) {
-            println!("Send back: {:?}", f);
-            my_spawn(sink_sender.send(f));
-        }
-        Ok(())
-    }));
-    my_spawn(sink.send_all(sink_receiver.map_err(|_| ws::Error::new(ws::ErrorKind::Protocol, "some error"))));
+/// A fresh UDP socket for one connection's datagrams to `target`.
+fn bridge_socket(target: SocketAddr) -> io::Result<UdpSocket> {
+    let any = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    UdpSocket::bind(&any.parse().unwrap())
+}
+
+/// Sends the WebSocket peer's datagrams on to `target`, and hands those
+/// `target` sends back to the connection through `to_peer`. Both stop once
+/// the connection, and with it the handler feeding `from_peer`, is gone.
+fn bridge(socket: UdpSocket, target: SocketAddr, format: BrgMsgFormat,
+          from_peer: mpsc::Receiver<Bytes>, to_peer: mpsc::Sender<Message>) {
+    let (sink, stream) = UdpFramed::new(socket, BytesCodec::new()).split();
+    let send = from_peer.map(move |data| (data, target))
+        .forward(sink.sink_map_err(|e| eprintln!("UDP bridge send error: {:?}", e)));
+    let receive = stream
+        // only the target may answer
+        .filter(move |(_, from)| *from == target)
+        .map(move |(data, _)| format.encode(&BrgMsg::SendData(data.freeze())))
+        .map_err(|e| eprintln!("UDP bridge receive error: {:?}", e))
+        .forward(to_peer.sink_map_err(|_| ()));
+    tokio::spawn(send.select2(receive).then(|_| Ok(())));
 }
 
 use tokio::executor::Spawn;
@@ -193,18 +183,123 @@ fn my_spawn<T, E, F>(f: F) -> Spawn where E: std::fmt::Debug, F: Future<Item=T,
 }
 
 pub fn test() {
-    let addr = "0.0.0.0:8080".parse::<SocketAddr>().unwrap();
-    let tcp = TcpListener::bind(&addr).unwrap();
-    let server = Builder::new(
-        tcp.incoming(), Http::new()
-    ).serve(|| {
-        service_fn_ok(ws_upgrade)
-    });
-    tokio::run(server.then(|res| {
-        match res {
-            Ok(_) => Ok(()),
-            Err(_) => Err(()),
+    serve(ServerConfig::default())
+}
+
+/// The client end: datagrams `app` sends to `local` go over a WebSocket to
+/// the server `config` names, and what comes back is sent on to `app`.
+pub fn client(config: ClientConfig, local: SocketAddr, app: SocketAddr) {
+    let socket = UdpSocket::bind(&local).expect("Failed to bind the local UDP socket");
+    tokio::run(ws_connect(config)
+        .and_then(move |(framed, accepted)| {
+            let format = accepted.subprotocol.as_ref()
+                .and_then(|name| BrgMsgFormat::from_subprotocol(name))
+                .unwrap_or(BrgMsgFormat::Binary);
+            let (to_server, from_app) = mpsc::channel(BRIDGE_QUEUE);
+            let handler = BrgHandler { format, identity: None, bridge: Some(to_server), client_addr: None };
+            let mut conn = Connection::new(framed, handler);
+            bridge(socket, app, format, from_app, conn.sender(BRIDGE_QUEUE));
+            conn
+        })
+        .map_err(|e| eprintln!("Client error: {:?}", e)))
+}
+
+/// Loads the certificate to serve wss:// with, if any.
+fn load_tls(config: &ServerConfig) -> Option<ServerTls> {
+    config.tls.as_ref().map(|tls| ServerTls::load(tls).expect("Failed to load TLS certificate"))
+}
+
+/// The acceptor for the next connection, after looking for a renewed
+/// certificate if it is time to.
+fn tls_acceptor(tls: &mut Option<ServerTls>) -> Option<TlsAcceptor> {
+    tls.as_mut().map(|tls| {
+        if let Err(e) = tls.reload_due() {
+            eprintln!("TLS reload error, keeping the current certificate: {}", e);
         }
-    }))
-    // service_fn_ok(service_fn(ws_upgrade)));
-}
\ No newline at end of file
+        tls.acceptor()
+    })
+}
+
+/// Reads the PROXY protocol header off a new connection if the config
+/// expects one, resolving to the stream and the client address: the one in
+/// the header, or else the peer's.
+fn proxy_header(config: &ServerConfig, sock: TcpStream) -> impl Future<Item = (TcpStream, Option<SocketAddr>), Error = Error> {
+    let peer = sock.peer_addr().ok();
+    if !config.proxy_protocol {
+        return Either::B(future::ok((sock, peer)));
+    }
+    // the balancer's own health checks carry no client address
+    Either::A(ProxyHeader::new(sock)
+        .map(move |(sock, addr)| (sock, addr.or(peer)))
+        .timeout(PROXY_HEADER_TIMEOUT)
+        .map_err(|e| e.into_inner().unwrap_or_else(|| {
+            Error::Handshake("timed out reading the proxy protocol header".into())
+        })))
+}
+
+fn accept_tls(acceptor: Option<TlsAcceptor>, sock: TcpStream) -> impl Future<Item = ServerStream, Error = io::Error> {
+    match acceptor {
+        Some(acceptor) => Either::A(acceptor.accept(sock).map(MaybeTls::Tls)),
+        None => Either::B(future::ok(MaybeTls::Plain(sock))),
+    }
+}
+
+pub fn serve(config: ServerConfig) {
+    let tcp = TcpListener::bind(&config.addr).unwrap();
+    let mut tls = load_tls(&config);
+    config.ready.store(true, Ordering::SeqCst);
+    let config = Arc::new(config);
+    tokio::run(tcp.incoming()
+        .map_err(|e| eprintln!("accept error: {:?}", e))
+        .for_each(move |sock| {
+            let config = config.clone();
+            let acceptor = tls_acceptor(&mut tls);
+            // the PROXY header comes ahead of the TLS handshake
+            my_spawn(proxy_header(&config, sock).from_err::<Box<dyn std::error::Error + Send + Sync>>()
+                .and_then(move |(sock, peer)| {
+                    accept_tls(acceptor, sock).map(move |stream| (stream, peer)).from_err()
+                })
+                .and_then(move |(stream, peer)| {
+                    let peer = peer.map(|addr| addr.ip());
+                    let client_cert = stream.client_identity();
+                    let service = service_fn_ok(move |req| ws_upgrade(req, peer, client_cert.as_ref(), &config));
+                    Http::new().serve_connection(stream, service).with_upgrades().from_err()
+                }));
+            Ok(())
+        }))
+}
+
+/// Same as `serve`, doing the upgrade handshake itself instead of through hyper.
+pub fn serve_native(config: ServerConfig) {
+    let tcp = TcpListener::bind(&config.addr).unwrap();
+    let mut tls = load_tls(&config);
+    config.ready.store(true, Ordering::SeqCst);
+    let config = Arc::new(config);
+    tokio::run(tcp.incoming()
+        .map_err(|e| eprintln!("accept error: {:?}", e))
+        .for_each(move |sock| {
+            let config = config.clone();
+            let acceptor = tls_acceptor(&mut tls);
+            let session_config = config.clone();
+            // the PROXY header comes ahead of the TLS handshake
+            my_spawn(proxy_header(&config, sock).and_then(move |(sock, peer)| {
+                accept_tls(acceptor, sock).map(move |stream| (stream, peer)).from_err()
+            }).and_then(move |(stream, peer)| {
+                let client_cert = stream.client_identity();
+                let mut session = WsSession::new(stream, session_config);
+                if let Some(addr) = peer {
+                    session = session.peer(addr);
+                }
+                match client_cert {
+                    Some(identity) => session.client_cert(identity),
+                    None => session,
+                }
+            }).map(move |upgraded| {
+                if let Some((framed, negotiated)) = upgraded {
+                    println!("WS Upgraded");
+                    process_upgraded(framed, negotiated, &config);
+                }
+            }));
+            Ok(())
+        }))
+}
@@ -1,7 +1,7 @@
 extern crate tokio_timer;
 extern crate tokio_udp;
 
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{BufMut, BytesMut};
 use futures::prelude::*;
 use futures::Future;
 use futures::Stream;
@@ -12,10 +12,9 @@ use tokio_codec::BytesCodec;
 use tokio_udp::{UdpFramed, UdpSocket};
 
 use std::io::Error as IOError;
-use std::io::ErrorKind as IOErrorKind;
 
 fn blank_io_error() -> IOError {
-    IOError::new(IOErrorKind::Other, "some error")
+    IOError::other("some error")
 }
 
 // fn general_handler<T, E>(res: Result<T, E>) -> Result<(), std::io::Error> where E: std::fmt::Debug {
@@ -49,7 +48,7 @@ fn test_msg() -> impl Future<Item = (), Error = ()> {
                     println!("Sending test msg[{}], instant = {:?}", msg_id, i);
                     let s: String = format!("a test msg, no. {}", msg_id);
                     let mut buf = BytesMut::new();
-                    buf.reserve(s.as_bytes().len());
+                    buf.reserve(s.len());
                     buf.put(s);
                     (buf.freeze(), to_addr)
                 }),
@@ -74,7 +73,7 @@ pub fn test() {
             let s: String = format!("FROM {:?} ECHO {}", src_addr, msg);
             println!("Recevied DGRAM {}", s);
             let mut buf = BytesMut::new();
-            buf.reserve(s.as_bytes().len());
+            buf.reserve(s.len());
             buf.put(s);
             // sends ECHO msg back to where it's from
             (buf.freeze(), src_addr)
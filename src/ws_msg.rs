@@ -2,8 +2,9 @@ extern crate bytes;
 extern crate tokio_codec;
 
 use bytes::{Bytes, BytesMut, IntoBuf, Buf, BufMut};
-use tokio::codec::{Encoder, Decoder};
-use std::convert::{TryFrom, TryInto, From, Into};
+use std::convert::{TryFrom, From};
+
+use crate::wsproto::Message;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum FailReason {
@@ -24,6 +25,8 @@ pub enum BrgMsg {
 pub enum BrgMsgParseError {
     InvalidOp(u8),
     CorruptedMessage,
+    /// A Text message in the binary format, or the other way around.
+    UnexpectedMessage,
 }
 
 use FailReason::*;
@@ -38,17 +41,17 @@ impl TryFrom<u8> for FailReason {
     }
 }
 
-impl Into<u8> for &FailReason {
-    fn into(self: Self) -> u8 {
-        match self {
+impl From<&FailReason> for u8 {
+    fn from(reason: &FailReason) -> u8 {
+        match reason {
             UnknownFail => 0,
         }
     }
 }
 
-impl Into<u8> for FailReason {
-    fn into(self: Self) -> u8 {
-        (&self).into()
+impl From<FailReason> for u8 {
+    fn from(reason: FailReason) -> u8 {
+        (&reason).into()
     }
 }
 
@@ -61,10 +64,10 @@ const U8_SIZE: usize = std::mem::size_of::<u8>();
 impl TryFrom<&Bytes> for BrgMsg {
     type Error = BrgMsgParseError;
     fn try_from(src: &Bytes) -> Result<Self, Self::Error> {
-        if src.len() == 0 {
+        if src.is_empty() {
             return Err(CorruptedMessage);
         }
-        let op_code : u8 = src.get(0).unwrap().clone();
+        let op_code : u8 = src[0];
         match op_code {
             0 => if src.len() == 1 {
                 Ok(ReqSession)
@@ -118,9 +121,9 @@ impl TryFrom<&Bytes> for BrgMsg {
     }
 }
 
-impl Into<Bytes> for &BrgMsg {
-    fn into(self: Self) -> Bytes {
-        let (op_code, size): (u8, usize) = match self {
+impl From<&BrgMsg> for Bytes {
+    fn from(msg: &BrgMsg) -> Bytes {
+        let (op_code, size): (u8, usize) = match msg {
             ReqSession => (0, 1),
             ReqSessionReply(_) => (1, 1 + U64_SIZE),
             SetSession(_) => (2, 1 + U64_SIZE),
@@ -130,8 +133,8 @@ impl Into<Bytes> for &BrgMsg {
         };
         let mut bytes = BytesMut::with_capacity(size);
         bytes.put_u8(op_code);
-        match self {
-            ReqSessionReply(id) | SetSession(id) => bytes.put_u64_be(id.clone()),
+        match msg {
+            ReqSessionReply(id) | SetSession(id) => bytes.put_u64_be(*id),
             SendData(data) => bytes.put_slice(data),
             Fail(reason) => bytes.put_u8(reason.into()),
             _ => (),
@@ -140,9 +143,56 @@ impl Into<Bytes> for &BrgMsg {
     }
 }
 
-impl Into<Bytes> for BrgMsg {
-    fn into(self: BrgMsg) -> Bytes {
-        (&self).into()
+impl From<BrgMsg> for Bytes {
+    fn from(msg: BrgMsg) -> Bytes {
+        (&msg).into()
+    }
+}
+
+/// How `BrgMsg`s are carried over WebSocket, negotiated as a subprotocol so
+/// the wire format can be versioned.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BrgMsgFormat {
+    /// One `BrgMsg` per Binary message.
+    Binary,
+    /// One base64 encoded `BrgMsg` per Text message, for clients that cannot
+    /// send binary data.
+    Base64,
+}
+
+impl BrgMsgFormat {
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            BrgMsgFormat::Binary => "wsbrg.v1",
+            BrgMsgFormat::Base64 => "wsbrg.v1.base64",
+        }
+    }
+
+    pub fn from_subprotocol(name: &str) -> Option<BrgMsgFormat> {
+        [BrgMsgFormat::Binary, BrgMsgFormat::Base64]
+            .iter()
+            .cloned()
+            .find(|f| f.subprotocol() == name)
+    }
+
+    pub fn encode(self, msg: &BrgMsg) -> Message {
+        let bytes: Bytes = msg.into();
+        match self {
+            BrgMsgFormat::Binary => Message::Binary(BytesMut::from(bytes)),
+            BrgMsgFormat::Base64 => Message::Text(BytesMut::from(base64::encode(&bytes))),
+        }
+    }
+
+    /// Decodes a Text or Binary message, whichever this format uses.
+    pub fn decode(self, msg: Message) -> Result<BrgMsg, BrgMsgParseError> {
+        let bytes = match (self, msg) {
+            (BrgMsgFormat::Binary, Message::Binary(b)) => b.freeze(),
+            (BrgMsgFormat::Base64, Message::Text(t)) => {
+                Bytes::from(base64::decode(&t).map_err(|_| CorruptedMessage)?)
+            },
+            _ => return Err(UnexpectedMessage),
+        };
+        BrgMsg::try_from(&bytes)
     }
 }
 
@@ -252,4 +302,27 @@ mod test {
         let bytes = Bytes::from_static(&FAIL_BYTES);
         assert_eq!(BrgMsg::try_from(&bytes).unwrap(), Fail(UnknownFail));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_format_subprotocol() {
+        for &format in &[BrgMsgFormat::Binary, BrgMsgFormat::Base64] {
+            assert_eq!(BrgMsgFormat::from_subprotocol(format.subprotocol()), Some(format));
+        }
+        assert_eq!(BrgMsgFormat::from_subprotocol("wsbrg.v0"), None);
+    }
+
+    #[test]
+    fn test_format_roundtrip() {
+        let msg = SetSession(0x0101010101010101);
+        let binary = BrgMsgFormat::Binary.encode(&msg);
+        assert_eq!(binary, Message::Binary(BytesMut::from(&SET_SESSION_BYTES[..])));
+        assert_eq!(BrgMsgFormat::Binary.decode(binary).unwrap(), msg);
+
+        let text = BrgMsgFormat::Base64.encode(&msg);
+        assert_eq!(text, Message::Text(BytesMut::from("AgEBAQEBAQEB")));
+        assert_eq!(BrgMsgFormat::Base64.decode(text.clone()).unwrap(), msg);
+
+        assert!(BrgMsgFormat::Binary.decode(text).is_err());
+        assert!(BrgMsgFormat::Base64.decode(Message::Text(BytesMut::from("not base64!"))).is_err());
+    }
+}
@@ -0,0 +1,380 @@
+extern crate flate2;
+
+use super::{Error, ProtocolError};
+
+use bytes::BytesMut;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+
+/// Every compressed message ends with an empty stored block, which is left
+/// off on the wire (RFC 7692 section 7.2.1).
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const EXTENSION_NAME: &str = "permessage-deflate";
+
+/// permessage-deflate parameters (RFC 7692).
+///
+/// Before the handshake this holds our own preferences, afterwards the
+/// parameters both sides agreed on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeflateConfig {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: u8,
+    pub client_max_window_bits: u8,
+    /// Largest message we are willing to inflate. This is a local limit and
+    /// is never sent to the peer.
+    pub max_inflated_size: usize,
+}
+
+impl Default for DeflateConfig {
+    fn default() -> DeflateConfig {
+        DeflateConfig {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: 15,
+            client_max_window_bits: 15,
+            max_inflated_size: 16 * (1 << 20),
+        }
+    }
+}
+
+/// Splits `name=value` and strips the optional quotes around `value`.
+fn split_param(param: &str) -> (&str, Option<&str>) {
+    let mut kv = param.splitn(2, '=');
+    let name = kv.next().unwrap_or("").trim();
+    let value = kv.next().map(|v| v.trim().trim_matches('"'));
+    (name, value)
+}
+
+/// Parses a window size. zlib cannot produce 8 bit windows, so those are
+/// treated like any other value we cannot honour.
+fn parse_window_bits(value: Option<&str>) -> Option<u8> {
+    match value.and_then(|v| v.parse::<u8>().ok()) {
+        Some(bits) if (9..=15).contains(&bits) => Some(bits),
+        _ => None,
+    }
+}
+
+/// Yields the parameter lists of every permessage-deflate offer in a
+/// `Sec-WebSocket-Extensions` value.
+fn deflate_offers<'a>(header: &'a str) -> impl Iterator<Item = Vec<&'a str>> + 'a {
+    header.split(',').filter_map(|offer| {
+        let mut parts = offer.split(';').map(str::trim);
+        if parts.next() == Some(EXTENSION_NAME) {
+            Some(parts.filter(|p| !p.is_empty()).collect())
+        } else {
+            None
+        }
+    })
+}
+
+impl DeflateConfig {
+    /// Server side: picks the first offer in the client's
+    /// `Sec-WebSocket-Extensions` header that we can accept.
+    pub fn negotiate(&self, offers: &str) -> Option<DeflateConfig> {
+        deflate_offers(offers).filter_map(|params| self.accept_offer(&params)).next()
+    }
+
+    fn accept_offer(&self, params: &[&str]) -> Option<DeflateConfig> {
+        let mut agreed = self.clone();
+        let mut seen: Vec<&str> = Vec::with_capacity(params.len());
+        let mut client_window_offered = false;
+        for param in params {
+            let (name, value) = split_param(param);
+            if seen.contains(&name) {
+                return None;
+            }
+            seen.push(name);
+            match name {
+                "server_no_context_takeover" if value.is_none() => {
+                    agreed.server_no_context_takeover = true;
+                }
+                "client_no_context_takeover" if value.is_none() => {
+                    agreed.client_no_context_takeover = true;
+                }
+                "server_max_window_bits" => {
+                    let bits = parse_window_bits(value)?;
+                    agreed.server_max_window_bits = agreed.server_max_window_bits.min(bits);
+                }
+                "client_max_window_bits" => {
+                    client_window_offered = true;
+                    if value.is_some() {
+                        let bits = parse_window_bits(value)?;
+                        agreed.client_max_window_bits = agreed.client_max_window_bits.min(bits);
+                    }
+                }
+                _ => return None,
+            }
+        }
+        // the client's window can only be limited if it said it supports that
+        if !client_window_offered {
+            agreed.client_max_window_bits = 15;
+        }
+        Some(agreed)
+    }
+
+    /// The extension as written in the server's `Sec-WebSocket-Extensions`.
+    pub fn to_header(&self) -> String {
+        let mut header = String::from(EXTENSION_NAME);
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        if self.server_max_window_bits < 15 {
+            header.push_str(&format!("; server_max_window_bits={}", self.server_max_window_bits));
+        }
+        if self.client_max_window_bits < 15 {
+            header.push_str(&format!("; client_max_window_bits={}", self.client_max_window_bits));
+        }
+        header
+    }
+
+    /// Client side: the offer to put in the request's `Sec-WebSocket-Extensions`.
+    pub fn offer(&self) -> String {
+        let mut header = self.to_header();
+        if self.client_max_window_bits == 15 {
+            // lets the server limit our window if it wants to
+            header.push_str("; client_max_window_bits");
+        }
+        header
+    }
+
+    /// Client side: checks the server's response to our `offer`.
+    pub fn accept_response(&self, header: &str) -> Result<DeflateConfig, Error> {
+        let bad_response = |details: String| Error::Handshake(details.into());
+        let mut responses = deflate_offers(header);
+        let params = match (responses.next(), responses.next()) {
+            (Some(params), None) => params,
+            _ => {
+                return Err(bad_response(format!(
+                    "Expected a single {} extension in the response: {}.",
+                    EXTENSION_NAME, header
+                )))
+            }
+        };
+        let mut agreed = DeflateConfig {
+            max_inflated_size: self.max_inflated_size,
+            ..DeflateConfig::default()
+        };
+        for param in params {
+            let (name, value) = split_param(param);
+            match name {
+                "server_no_context_takeover" if value.is_none() => {
+                    agreed.server_no_context_takeover = true;
+                }
+                "client_no_context_takeover" if value.is_none() => {
+                    agreed.client_no_context_takeover = true;
+                }
+                "server_max_window_bits" if parse_window_bits(value).is_some() => {
+                    agreed.server_max_window_bits = parse_window_bits(value).unwrap();
+                }
+                "client_max_window_bits" if parse_window_bits(value).is_some() => {
+                    agreed.client_max_window_bits = parse_window_bits(value).unwrap();
+                }
+                _ => {
+                    return Err(bad_response(format!(
+                        "Unexpected {} parameter in the response: {}.",
+                        EXTENSION_NAME, param
+                    )))
+                }
+            }
+        }
+        if agreed.server_max_window_bits > self.server_max_window_bits {
+            return Err(bad_response(format!(
+                "Server window of {} bits exceeds the offered {}.",
+                agreed.server_max_window_bits, self.server_max_window_bits
+            )));
+        }
+        agreed.client_max_window_bits = agreed.client_max_window_bits.min(self.client_max_window_bits);
+        agreed.client_no_context_takeover |= self.client_no_context_takeover;
+        Ok(agreed)
+    }
+
+    /// Compressor and decompressor for the server end of the connection.
+    pub fn server_side(&self) -> (Deflater, Inflater) {
+        (
+            Deflater::new(self.server_no_context_takeover, self.server_max_window_bits),
+            Inflater::new(self.client_no_context_takeover, self.max_inflated_size),
+        )
+    }
+
+    /// Compressor and decompressor for the client end of the connection.
+    pub fn client_side(&self) -> (Deflater, Inflater) {
+        (
+            Deflater::new(self.client_no_context_takeover, self.client_max_window_bits),
+            Inflater::new(self.server_no_context_takeover, self.max_inflated_size),
+        )
+    }
+}
+
+/// Compresses outgoing messages.
+pub struct Deflater {
+    inner: Compress,
+    no_context_takeover: bool,
+}
+
+impl Deflater {
+    pub fn new(no_context_takeover: bool, window_bits: u8) -> Self {
+        Deflater {
+            inner: Compress::new_with_window_bits(Compression::default(), false, window_bits.max(9)),
+            no_context_takeover,
+        }
+    }
+
+    pub fn compress(&mut self, data: &[u8]) -> Result<BytesMut, Error> {
+        let mut out: Vec<u8> = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.inner.total_in();
+        loop {
+            if out.capacity() - out.len() < 64 {
+                let extra = out.len().max(64);
+                out.reserve(extra);
+            }
+            let consumed = (self.inner.total_in() - start) as usize;
+            self.inner
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|e| Error::Internal(format!("deflate error: {}", e).into()))?;
+            let consumed = (self.inner.total_in() - start) as usize;
+            // a sync flush is complete once it leaves room in the output
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if out.ends_with(&DEFLATE_TAIL) {
+            out.truncate(out.len() - DEFLATE_TAIL.len());
+        }
+        if self.no_context_takeover {
+            self.inner.reset();
+        }
+        Ok(BytesMut::from(out))
+    }
+}
+
+/// Inflates incoming messages, refusing any that grow past `max_size`.
+pub struct Inflater {
+    inner: Decompress,
+    no_context_takeover: bool,
+    max_size: usize,
+}
+
+impl Inflater {
+    pub fn new(no_context_takeover: bool, max_size: usize) -> Self {
+        Inflater {
+            inner: Decompress::new(false),
+            no_context_takeover,
+            max_size,
+        }
+    }
+
+    pub fn decompress(&mut self, mut data: BytesMut) -> Result<BytesMut, Error> {
+        data.extend_from_slice(&DEFLATE_TAIL);
+        let mut out: Vec<u8> = Vec::with_capacity((data.len() * 2).min(self.max_size + 1));
+        let start_in = self.inner.total_in();
+        loop {
+            if out.len() == out.capacity() {
+                let extra = out.len().max(4096).min(self.max_size + 1 - out.len());
+                out.reserve_exact(extra);
+            }
+            let consumed = (self.inner.total_in() - start_in) as usize;
+            let before_out = self.inner.total_out();
+            self.inner
+                .decompress_vec(&data[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|e| ProtocolError::BadCompressedData(e.to_string()))?;
+            if out.len() > self.max_size {
+                return Err(Error::Capacity(
+                    format!(
+                        "Inflated message exceeds the defined max: {}.",
+                        self.max_size
+                    )
+                    .into(),
+                ));
+            }
+            let consumed_now = (self.inner.total_in() - start_in) as usize;
+            if consumed_now == data.len() && out.len() < out.capacity() {
+                break;
+            }
+            // input left that inflate will not take, as after the end of
+            // the stream
+            if consumed_now == consumed && self.inner.total_out() == before_out {
+                return Err(ProtocolError::BadCompressedData("data after the end of the stream".into()).into());
+            }
+        }
+        if self.no_context_takeover {
+            self.inner.reset(false);
+        }
+        Ok(BytesMut::from(out))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wsproto::CloseCode;
+
+    #[test]
+    fn test_negotiate() {
+        let local = DeflateConfig::default();
+        let agreed = local
+            .negotiate("x-webkit-deflate-frame, permessage-deflate; client_max_window_bits; server_max_window_bits=10")
+            .unwrap();
+        assert_eq!(agreed.server_max_window_bits, 10);
+        assert_eq!(agreed.client_max_window_bits, 15);
+        assert_eq!(agreed.to_header(), "permessage-deflate; server_max_window_bits=10");
+
+        // the first offer has a parameter we do not know, so the second wins
+        let agreed = local
+            .negotiate("permessage-deflate; foo, permessage-deflate; server_no_context_takeover")
+            .unwrap();
+        assert!(agreed.server_no_context_takeover);
+
+        assert_eq!(local.negotiate("permessage-deflate; server_max_window_bits=8"), None);
+        assert_eq!(local.negotiate("x-webkit-deflate-frame"), None);
+    }
+
+    #[test]
+    fn test_accept_response() {
+        let local = DeflateConfig::default();
+        assert_eq!(local.offer(), "permessage-deflate; client_max_window_bits");
+        let agreed = local
+            .accept_response("permessage-deflate; client_max_window_bits=12")
+            .unwrap();
+        assert_eq!(agreed.client_max_window_bits, 12);
+        assert!(local.accept_response("permessage-deflate; bogus").is_err());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let (mut deflater, mut inflater) = DeflateConfig::default().server_side();
+        let data = b"game state delta game state delta game state delta";
+        for _ in 0..3 {
+            let compressed = deflater.compress(data).unwrap();
+            assert!(compressed.len() < data.len());
+            assert_eq!(&inflater.decompress(compressed).unwrap()[..], &data[..]);
+        }
+    }
+
+    #[test]
+    fn test_inflate_limit() {
+        let mut deflater = Deflater::new(false, 15);
+        let mut inflater = Inflater::new(false, 1024);
+        let compressed = deflater.compress(&[0u8; 4096]).unwrap();
+        let err = inflater.decompress(compressed).unwrap_err();
+        assert_eq!(err.close_code(), CloseCode::Size);
+    }
+
+    #[test]
+    fn test_inflate_bad_data() {
+        let bad = |data: &[u8]| {
+            let err = Inflater::new(false, 1 << 20).decompress(BytesMut::from(data)).unwrap_err();
+            assert_eq!(err.close_code(), CloseCode::Invalid);
+        };
+        bad(b"garbage!");
+        // a final empty stored block with more after it
+        bad(b"\x01\x00\x00\xff\xffzzz");
+
+        let data: Vec<u8> = (0..2000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let compressed = Deflater::new(false, 15).compress(&data).unwrap();
+        bad(&compressed[..compressed.len() / 2]);
+    }
+}
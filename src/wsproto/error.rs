@@ -0,0 +1,271 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::str::Utf8Error;
+
+/// Status codes carried by Close frames, see RFC 6455 section 7.4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    /// 1000, the purpose of the connection has been fulfilled.
+    Normal,
+    /// 1001, the endpoint is going away.
+    Away,
+    /// 1002, the peer broke the protocol.
+    Protocol,
+    /// 1003, the endpoint got a type of data it cannot accept.
+    Unsupported,
+    /// 1005, no status code was present. Never sent on the wire.
+    Status,
+    /// 1006, the connection was dropped without a Close. Never sent on the wire.
+    Abnormal,
+    /// 1007, a message's data did not match its type, e.g. non UTF-8 text.
+    Invalid,
+    /// 1008, a message violated the endpoint's policy.
+    Policy,
+    /// 1009, a message was too big to process.
+    Size,
+    /// 1010, the client expected the server to negotiate an extension.
+    Extension,
+    /// 1011, the server hit an unexpected condition.
+    Error,
+    /// 1012, the server is restarting.
+    Restart,
+    /// 1013, the server is overloaded, try again later.
+    Again,
+    /// 1015, the TLS handshake failed. Never sent on the wire.
+    Tls,
+    /// Any other code, including the 3000-4999 application range.
+    Other(u16),
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        use CloseCode::*;
+        match code {
+            1000 => Normal,
+            1001 => Away,
+            1002 => Protocol,
+            1003 => Unsupported,
+            1005 => Status,
+            1006 => Abnormal,
+            1007 => Invalid,
+            1008 => Policy,
+            1009 => Size,
+            1010 => Extension,
+            1011 => Error,
+            1012 => Restart,
+            1013 => Again,
+            1015 => Tls,
+            code => Other(code),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        use CloseCode::*;
+        match code {
+            Normal => 1000,
+            Away => 1001,
+            Protocol => 1002,
+            Unsupported => 1003,
+            Status => 1005,
+            Abnormal => 1006,
+            Invalid => 1007,
+            Policy => 1008,
+            Size => 1009,
+            Extension => 1010,
+            Error => 1011,
+            Restart => 1012,
+            Again => 1013,
+            Tls => 1015,
+            Other(code) => code,
+        }
+    }
+}
+
+/// Everything that can go wrong on a WebSocket connection.
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying stream failed, or the peer stopped responding.
+    Io(io::Error),
+    /// The opening handshake was malformed or refused.
+    Handshake(Cow<'static, str>),
+    /// The upgrade request used a method other than GET.
+    Method(Cow<'static, str>),
+    /// The client asked for a WebSocket version other than 13.
+    Version(Cow<'static, str>),
+    /// No route serves the path the upgrade request asked for.
+    NotFound(Cow<'static, str>),
+    /// The upgrade request carried no valid credentials.
+    Auth(Cow<'static, str>),
+    /// The peer broke RFC 6455.
+    Protocol(ProtocolError),
+    /// A frame or message went over the configured limits.
+    Capacity(Cow<'static, str>),
+    /// A text message or close reason was not valid UTF-8.
+    Utf8(Utf8Error),
+    /// The bridge refused something the peer asked for.
+    Policy(Cow<'static, str>),
+    /// Something failed on our side, e.g. the compressor or a timer.
+    Internal(Cow<'static, str>),
+    /// The peer closed the connection with this code.
+    CloseReceived(CloseCode),
+}
+
+impl Error {
+    /// The close code to send to the peer when a connection fails with this error.
+    pub fn close_code(&self) -> CloseCode {
+        match self {
+            Error::Io(_) | Error::Internal(_) => CloseCode::Error,
+            Error::Handshake(_) | Error::Method(_) | Error::Version(_) => CloseCode::Protocol,
+            Error::Protocol(e) => e.close_code(),
+            Error::Capacity(_) => CloseCode::Size,
+            Error::Utf8(_) => CloseCode::Invalid,
+            Error::Policy(_) | Error::Auth(_) | Error::NotFound(_) => CloseCode::Policy,
+            Error::CloseReceived(code) => *code,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Handshake(details) => write!(f, "handshake failed: {}", details),
+            Error::Method(method) => write!(f, "method not allowed: {}", method),
+            Error::Version(version) => write!(f, "unsupported websocket version: {}", version),
+            Error::NotFound(path) => write!(f, "no route for {}", path),
+            Error::Auth(details) => write!(f, "authentication failed: {}", details),
+            Error::Protocol(e) => write!(f, "protocol error: {}", e),
+            Error::Capacity(details) => write!(f, "size limit exceeded: {}", details),
+            Error::Utf8(e) => write!(f, "invalid UTF-8: {}", e),
+            Error::Policy(details) => write!(f, "policy violation: {}", details),
+            Error::Internal(details) => write!(f, "internal error: {}", details),
+            Error::CloseReceived(code) => write!(f, "closed by peer: {}", u16::from(*code)),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Protocol(e) => Some(e),
+            Error::Utf8(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<Utf8Error> for Error {
+    fn from(e: Utf8Error) -> Error {
+        Error::Utf8(e)
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(e: ProtocolError) -> Error {
+        Error::Protocol(e)
+    }
+}
+
+/// Ways a peer can violate RFC 6455.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    /// A control frame carried more than 125 bytes of payload.
+    ControlFrameTooLong(u64),
+    /// A control frame had `fin` unset.
+    FragmentedControlFrame,
+    /// RSV bits were set without an extension that defines them.
+    ReservedBits,
+    /// The opcode is reserved or unknown.
+    BadOpCode(u8),
+    /// The payload length was not written in its shortest form.
+    NonMinimalLength,
+    /// A 64-bit payload length had its most significant bit set.
+    LengthOverflow,
+    /// A client sent a frame without a mask.
+    UnmaskedFrame,
+    /// A server sent a masked frame.
+    MaskedFrame,
+    /// A new data frame arrived while a fragmented message was in progress.
+    InterleavedMessage,
+    /// A continuation frame arrived with no message to continue.
+    UnexpectedContinuation,
+    /// A Close payload was a single byte, too short for a status code.
+    TruncatedCloseCode,
+    /// A Close carried a status code that may not be sent on the wire.
+    InvalidCloseCode(u16),
+    /// A compressed message could not be inflated.
+    BadCompressedData(String),
+}
+
+impl ProtocolError {
+    pub fn close_code(&self) -> CloseCode {
+        match self {
+            // well framed, but the payload does not inflate
+            ProtocolError::BadCompressedData(_) => CloseCode::Invalid,
+            _ => CloseCode::Protocol,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ProtocolError::*;
+        match self {
+            ControlFrameTooLong(len) => write!(f, "control frame payload too long: {}", len),
+            FragmentedControlFrame => write!(f, "fragmented control frame"),
+            ReservedBits => write!(f, "reserved bits set without a negotiated extension"),
+            BadOpCode(v) => write!(f, "bad opcode: {}", v),
+            NonMinimalLength => write!(f, "payload length not minimally encoded"),
+            LengthOverflow => write!(f, "payload length has the most significant bit set"),
+            UnmaskedFrame => write!(f, "unmasked frame from client"),
+            MaskedFrame => write!(f, "masked frame from server"),
+            InterleavedMessage => write!(f, "new data frame while a fragmented message is in progress"),
+            UnexpectedContinuation => write!(f, "continuation frame with no message to continue"),
+            TruncatedCloseCode => write!(f, "close frame with a truncated status code"),
+            InvalidCloseCode(code) => write!(f, "close frame with invalid status code: {}", code),
+            BadCompressedData(details) => write!(f, "bad compressed data: {}", details),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_code_roundtrip() {
+        for &code in &[1000u16, 1001, 1002, 1003, 1005, 1006, 1007, 1008, 1009, 1010, 1011, 1012, 1013, 1015, 3000, 4999] {
+            assert_eq!(u16::from(CloseCode::from(code)), code);
+        }
+        assert_eq!(CloseCode::from(1009), CloseCode::Size);
+        assert_eq!(CloseCode::from(4000), CloseCode::Other(4000));
+    }
+
+    #[test]
+    fn test_error_close_code() {
+        let utf8 = String::from_utf8(vec![0xff]).unwrap_err().utf8_error();
+        assert_eq!(Error::from(utf8).close_code(), CloseCode::Invalid);
+        assert_eq!(Error::Capacity("too big".into()).close_code(), CloseCode::Size);
+        assert_eq!(Error::from(ProtocolError::MaskedFrame).close_code(), CloseCode::Protocol);
+        assert_eq!(Error::Policy("no".into()).close_code(), CloseCode::Policy);
+        assert_eq!(Error::Auth("no".into()).close_code(), CloseCode::Policy);
+        assert_eq!(Error::Version("8".into()).close_code(), CloseCode::Protocol);
+        assert_eq!(Error::Method("POST".into()).close_code(), CloseCode::Protocol);
+        assert_eq!(Error::NotFound("/x".into()).close_code(), CloseCode::Policy);
+        assert_eq!(Error::CloseReceived(CloseCode::Away).close_code(), CloseCode::Away);
+        let io = io::Error::new(io::ErrorKind::BrokenPipe, "gone");
+        assert_eq!(Error::from(io).close_code(), CloseCode::Error);
+    }
+}
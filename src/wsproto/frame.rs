@@ -1,6 +1,8 @@
 extern crate bytes;
 
-use bytes::{BigEndian, ByteOrder, BytesMut};
+use super::{CloseCode, Error, ProtocolError};
+
+use bytes::{BigEndian, BufMut, ByteOrder, BytesMut};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum OpCode {
@@ -35,10 +37,16 @@ impl From<u8> for OpCode {
     }
 }
 
-impl Into<u8> for OpCode {
-    fn into(self: Self) -> u8 {
+impl OpCode {
+    pub fn is_control(&self) -> bool {
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
+    }
+}
+
+impl From<OpCode> for u8 {
+    fn from(opcode: OpCode) -> u8 {
         use OpCode::*;
-        match self {
+        match opcode {
             Continue => 0,
             Text => 1,
             Binary => 2,
@@ -50,6 +58,41 @@ impl Into<u8> for OpCode {
     }
 }
 
+/// Status code and reason carried by a Close frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloseFrame {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+/// Control frame payloads are limited to 125 bytes, 2 of which hold the code.
+const MAX_CLOSE_REASON_LEN: usize = 123;
+
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+/// XORs `buf` with the repeating 4-byte `mask`, starting at the first mask byte.
+///
+/// Works a 64-bit word at a time. Words are read and written through byte
+/// arrays, so `buf` may have any length and start at any alignment.
+pub fn apply_mask(mask: [u8; 4], buf: &mut [u8]) {
+    let mask_word = u64::from_ne_bytes([
+        mask[0], mask[1], mask[2], mask[3], mask[0], mask[1], mask[2], mask[3],
+    ]);
+    let mut words = buf.chunks_exact_mut(8);
+    for word in &mut words {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(word);
+        let v = u64::from_ne_bytes(bytes) ^ mask_word;
+        word.copy_from_slice(&v.to_ne_bytes());
+    }
+    // the tail starts at a multiple of 8, so it lines up with mask[0] again
+    for (i, b) in words.into_remainder().iter_mut().enumerate() {
+        *b ^= mask[i & 0x03];
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Frame {
     pub fin: bool,
@@ -57,6 +100,8 @@ pub struct Frame {
     pub rsv2: bool,
     pub rsv3: bool,
     pub opcode: OpCode,
+    /// Masking key of a frame still masked. `WsCodec` unmasks frames as it
+    /// decodes them and picks the mask itself when encoding.
     pub mask: Option<[u8; 4]>,
     pub payload: BytesMut,
 }
@@ -76,53 +121,114 @@ impl Default for Frame {
 }
 
 impl Frame {
-    pub fn apply_mask(&mut self) -> bool {
-        match self.mask {
-            None => false,
-            Some(m) => {
-                let mu32 = BigEndian::read_u32(&m);
-                for i in 0..(self.payload.len() / 4) {
-                    let r = (i * 4)..((i + 1) * 4);
-                    let v = BigEndian::read_u32(&self.payload[r.clone()]);
-                    let v = mu32 ^ v;
-                    BigEndian::write_u32(&mut self.payload[r.clone()], v);
-                }
-                for i in (self.payload.len() & 0xFFFC)..self.payload.len() {
-                    let mu8 = m[i & 0x03];
-                    self.payload[i] ^= mu8;
+    pub fn close(code: CloseCode, reason: &str) -> Frame {
+        // cut the reason down to fit, without splitting a character
+        let mut len = reason.len().min(MAX_CLOSE_REASON_LEN);
+        while !reason.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut payload = BytesMut::with_capacity(2 + len);
+        payload.put_u16_be(code.into());
+        payload.put_slice(&reason.as_bytes()[..len]);
+        Self::close_bytes_mut(payload)
+    }
+
+    pub fn close_bytes_mut(b: BytesMut) -> Frame {
+        Frame {
+            opcode: OpCode::Close,
+            payload: b,
+            ..Frame::default()
+        }
+    }
+
+    /// Parses the (unmasked) payload of a Close frame.
+    ///
+    /// An empty payload is allowed and yields `None`.
+    pub fn parse_close(&self) -> Result<Option<CloseFrame>, Error> {
+        match self.payload.len() {
+            0 => Ok(None),
+            1 => Err(ProtocolError::TruncatedCloseCode.into()),
+            _ => {
+                let code = BigEndian::read_u16(&self.payload[..2]);
+                if !is_valid_close_code(code) {
+                    return Err(ProtocolError::InvalidCloseCode(code).into());
                 }
-                true
+                let reason = std::str::from_utf8(&self.payload[2..])?;
+                Ok(Some(CloseFrame {
+                    code: CloseCode::from(code),
+                    reason: reason.to_owned(),
+                }))
             }
         }
     }
 
-    pub fn pong() -> Frame {
-        Self::pong_bytes_mut(BytesMut::with_capacity(0))
+    /// A Continue frame, `fin` set on the last fragment of a message.
+    pub fn continuation(b: BytesMut, fin: bool) -> Frame {
+        Frame {
+            fin,
+            opcode: OpCode::Continue,
+            payload: b,
+            ..Frame::default()
+        }
     }
 
-    pub fn pong_bytes_mut(b: BytesMut) -> Frame {
-        let mut frame = Frame::default();
-        frame.opcode = OpCode::Pong;
-        frame.payload = b;
-        frame
+    /// Splits a data frame into fragments of at most `fragment_size` payload bytes.
+    ///
+    /// The first fragment keeps the opcode and RSV bits, the rest are Continue
+    /// frames, and only the last one carries this frame's `fin`. Control frames
+    /// cannot be fragmented and are returned as they are.
+    pub fn fragment(mut self, fragment_size: usize) -> Vec<Frame> {
+        let fragment_size = fragment_size.max(1);
+        if self.opcode.is_control() || self.payload.len() <= fragment_size {
+            return vec![self];
+        }
+        let fin = self.fin;
+        let mut rest = self.payload.split_off(fragment_size);
+        self.fin = false;
+        let mut frames = vec![self];
+        while rest.len() > fragment_size {
+            let payload = rest.split_to(fragment_size);
+            frames.push(Frame::continuation(payload, false));
+        }
+        frames.push(Frame::continuation(rest, fin));
+        frames
+    }
+}
+
+/// Frames only the tests build by hand.
+#[cfg(test)]
+impl Frame {
+    pub fn apply_mask(&mut self) -> bool {
+        match self.mask {
+            None => false,
+            Some(m) => {
+                apply_mask(m, &mut self.payload);
+                true
+            }
+        }
     }
 
     pub fn ping() -> Frame {
-        let mut frame = Frame::default();
-        frame.opcode = OpCode::Ping;
-        frame
+        Frame {
+            opcode: OpCode::Ping,
+            ..Frame::default()
+        }
     }
 
     pub fn text(s: &str) -> Frame {
-        Self::text_bytes_mut(BytesMut::from(s))
+        Frame {
+            opcode: OpCode::Text,
+            payload: BytesMut::from(s),
+            ..Frame::default()
+        }
     }
 
-    pub fn text_bytes_mut(b: BytesMut) -> Frame {
-        let mut frame = Frame::default();
-        frame.opcode = OpCode::Text;
-        frame.mask = None;
-        frame.payload = b;
-        frame
+    pub fn binary(b: &[u8]) -> Frame {
+        Frame {
+            opcode: OpCode::Binary,
+            payload: BytesMut::from(b),
+            ..Frame::default()
+        }
     }
 }
 
@@ -135,4 +241,97 @@ mod tests {
         assert_eq!(OpCode::Pong, OpCode::Pong);
         assert_ne!(OpCode::Ping, OpCode::Pong);
     }
+
+    fn apply_mask_bytewise(mask: [u8; 4], buf: &mut [u8]) {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+
+    #[test]
+    fn test_apply_mask() {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let data: Vec<u8> = (0..70_000u32).map(|i| (i * 7) as u8).collect();
+        // lengths around the word size, and past the 64 KiB mark
+        for &len in &[0, 1, 3, 4, 7, 8, 9, 15, 16, 17, 65_535, 65_536, 65_539, 70_000] {
+            // offsets into the same buffer start the slice off word alignment
+            for offset in 0..3 {
+                let len = len - offset.min(len);
+                let mut expected = data.clone();
+                apply_mask_bytewise(mask, &mut expected[offset..offset + len]);
+                let mut actual = data.clone();
+                apply_mask(mask, &mut actual[offset..offset + len]);
+                assert_eq!(actual, expected, "len = {}, offset = {}", len, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn test_frame_apply_mask() {
+        let mut frame = Frame::text("hello");
+        assert!(!frame.apply_mask());
+        frame.mask = Some([1, 2, 3, 4]);
+        assert!(frame.apply_mask());
+        assert_eq!(&frame.payload[..], b"\x69\x67\x6f\x68\x6e");
+    }
+
+    #[test]
+    fn test_close_roundtrip() {
+        let frame = Frame::close(CloseCode::Policy, "go away");
+        assert_eq!(frame.opcode, OpCode::Close);
+        assert_eq!(&frame.payload[..], b"\x03\xf0go away");
+        assert_eq!(
+            frame.parse_close().unwrap(),
+            Some(CloseFrame {
+                code: CloseCode::Policy,
+                reason: "go away".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_close_reason_truncated() {
+        let reason = "\u{e9}".repeat(100);
+        let frame = Frame::close(CloseCode::Normal, &reason);
+        assert!(frame.payload.len() <= 125);
+        assert!(frame.parse_close().is_ok());
+    }
+
+    #[test]
+    fn test_fragment() {
+        let frames = Frame::binary(b"abcdefg").fragment(3);
+        let summary: Vec<_> = frames
+            .iter()
+            .map(|f| (f.opcode.clone(), f.fin, &f.payload[..]))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (OpCode::Binary, false, &b"abc"[..]),
+                (OpCode::Continue, false, &b"def"[..]),
+                (OpCode::Continue, true, &b"g"[..]),
+            ]
+        );
+
+        // an exact multiple leaves no empty trailing fragment
+        let frames = Frame::binary(b"abcdef").fragment(3);
+        assert_eq!(frames.len(), 2);
+        assert!(frames[1].fin);
+
+        // RSV bits stay on the first fragment only
+        let frames = Frame { rsv1: true, ..Frame::text("hello") }.fragment(2);
+        assert!(frames[0].rsv1 && !frames[1].rsv1 && !frames[2].rsv1);
+
+        assert_eq!(Frame::binary(b"abc").fragment(3).len(), 1);
+        assert_eq!(Frame::ping().fragment(1).len(), 1);
+    }
+
+    #[test]
+    fn test_parse_close_invalid() {
+        assert_eq!(Frame::close_bytes_mut(BytesMut::new()).parse_close().unwrap(), None);
+        let bad = |b: &[u8]| Frame::close_bytes_mut(BytesMut::from(b)).parse_close().is_err();
+        assert!(bad(b"\x03"));
+        assert!(bad(b"\x03\xed"));
+        assert!(bad(b"\x03\xe8\xff"));
+    }
 }
@@ -0,0 +1,374 @@
+use super::deflate::{DeflateConfig, Deflater, Inflater};
+use super::{CloseFrame, Error, Frame, OpCode, ProtocolError, Role, WsCodec};
+
+use bytes::BytesMut;
+use tokio::codec::{Decoder, Encoder};
+
+/// A complete WebSocket message, with any fragmentation already undone.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(BytesMut),
+    Binary(BytesMut),
+    Ping(BytesMut),
+    Pong(BytesMut),
+    Close(Option<CloseFrame>),
+}
+
+impl Message {
+    pub fn opcode(&self) -> OpCode {
+        match self {
+            Message::Text(_) => OpCode::Text,
+            Message::Binary(_) => OpCode::Binary,
+            Message::Ping(_) => OpCode::Ping,
+            Message::Pong(_) => OpCode::Pong,
+            Message::Close(_) => OpCode::Close,
+        }
+    }
+}
+
+impl From<Message> for Frame {
+    fn from(msg: Message) -> Frame {
+        let opcode = msg.opcode();
+        let payload = match msg {
+            Message::Text(b) | Message::Binary(b) | Message::Ping(b) | Message::Pong(b) => b,
+            Message::Close(Some(c)) => return Frame::close(c.code, &c.reason),
+            Message::Close(None) => BytesMut::new(),
+        };
+        Frame {
+            opcode,
+            payload,
+            ..Frame::default()
+        }
+    }
+}
+
+/// Gathers a Text/Binary frame and its continuation frames into one `Message`.
+///
+/// Control frames are complete on their own and are passed through right away,
+/// even while a fragmented message is in progress.
+///
+/// Text messages are checked to be valid UTF-8 as their fragments arrive, so a
+/// bad message is rejected before it has been fully received. Compressed
+/// messages can only be checked once they are inflated.
+pub struct Reassembler {
+    /// Opcode, payload so far and whether the message is compressed.
+    partial: Option<(OpCode, BytesMut, bool)>,
+    /// How much of a partial Text message is known to be valid UTF-8.
+    utf8_checked: usize,
+    inflater: Option<Inflater>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Reassembler {
+            partial: None,
+            utf8_checked: 0,
+            inflater: None,
+        }
+    }
+
+    /// Inflates messages that arrive with RSV1 set (permessage-deflate).
+    pub fn inflater(mut self, inflater: Inflater) -> Self {
+        self.inflater = Some(inflater);
+        self
+    }
+
+    /// Whether a fragmented message has been started but not finished yet.
+    #[cfg(test)]
+    pub fn in_progress(&self) -> bool {
+        self.partial.is_some()
+    }
+
+    /// Takes frames as decoded by `WsCodec`, that is, already unmasked.
+    pub fn push(&mut self, frame: Frame) -> Result<Option<Message>, Error> {
+        let is_data = frame.opcode == OpCode::Text || frame.opcode == OpCode::Binary;
+        if frame.rsv1 && (self.inflater.is_none() || !is_data) {
+            return Err(ProtocolError::ReservedBits.into());
+        }
+        match frame.opcode {
+            OpCode::Ping => Ok(Some(Message::Ping(frame.payload))),
+            OpCode::Pong => Ok(Some(Message::Pong(frame.payload))),
+            OpCode::Close => Ok(Some(Message::Close(frame.parse_close()?))),
+            OpCode::Text | OpCode::Binary => {
+                if self.partial.is_some() {
+                    return Err(ProtocolError::InterleavedMessage.into());
+                }
+                self.append(frame.opcode, frame.payload, frame.rsv1, frame.fin)
+            }
+            OpCode::Continue => match self.partial.take() {
+                Some((opcode, mut buf, compressed)) => {
+                    buf.extend_from_slice(&frame.payload);
+                    self.append(opcode, buf, compressed, frame.fin)
+                }
+                None => Err(ProtocolError::UnexpectedContinuation.into()),
+            },
+            OpCode::Bad(v) => Err(ProtocolError::BadOpCode(v).into()),
+        }
+    }
+
+    fn append(
+        &mut self,
+        opcode: OpCode,
+        buf: BytesMut,
+        compressed: bool,
+        fin: bool,
+    ) -> Result<Option<Message>, Error> {
+        if opcode == OpCode::Text && !compressed {
+            self.check_utf8(&buf, fin)?;
+        }
+        if !fin {
+            self.partial = Some((opcode, buf, compressed));
+            return Ok(None);
+        }
+        self.utf8_checked = 0;
+        let buf = match self.inflater {
+            Some(ref mut inflater) if compressed => {
+                let buf = inflater.decompress(buf)?;
+                if opcode == OpCode::Text {
+                    self.check_utf8(&buf, true)?;
+                    self.utf8_checked = 0;
+                }
+                buf
+            }
+            _ => buf,
+        };
+        match opcode {
+            OpCode::Text => Ok(Some(Message::Text(buf))),
+            _ => Ok(Some(Message::Binary(buf))),
+        }
+    }
+
+    /// Validates the part of `buf` not checked yet. A multi-byte sequence cut
+    /// off at the end is left for the next fragment, unless this is the last.
+    fn check_utf8(&mut self, buf: &[u8], fin: bool) -> Result<(), Error> {
+        match std::str::from_utf8(&buf[self.utf8_checked..]) {
+            Ok(_) => {
+                self.utf8_checked = buf.len();
+                Ok(())
+            }
+            Err(e) => {
+                if e.error_len().is_none() && !fin {
+                    self.utf8_checked += e.valid_up_to();
+                    Ok(())
+                } else {
+                    self.utf8_checked = 0;
+                    Err(Error::from(e))
+                }
+            }
+        }
+    }
+}
+
+/// `WsCodec` with a `Reassembler` on top, decoding whole messages.
+pub struct MessageCodec {
+    codec: WsCodec,
+    reassembler: Reassembler,
+    deflater: Option<Deflater>,
+    max_frame_size: Option<usize>,
+}
+
+impl MessageCodec {
+    pub fn new(codec: WsCodec) -> Self {
+        MessageCodec {
+            codec,
+            reassembler: Reassembler::new(),
+            deflater: None,
+            max_frame_size: None,
+        }
+    }
+
+    /// Fragments outgoing data messages so that no frame carries more than
+    /// `size` payload bytes, for intermediaries that choke on huge frames.
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = Some(size);
+        self
+    }
+
+    /// Compresses outgoing and inflates incoming data messages with the
+    /// negotiated permessage-deflate parameters.
+    pub fn deflate(self, config: &DeflateConfig) -> Self {
+        let (deflater, inflater) = match self.codec.role() {
+            Role::Server => config.server_side(),
+            Role::Client => config.client_side(),
+        };
+        MessageCodec {
+            codec: self.codec.reserved_bits(RSV1),
+            reassembler: self.reassembler.inflater(inflater),
+            deflater: Some(deflater),
+            max_frame_size: self.max_frame_size,
+        }
+    }
+}
+
+/// The reserved bit permessage-deflate uses to mark compressed messages.
+const RSV1: u8 = 0x40;
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = Error;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        while let Some(frame) = self.codec.decode(src)? {
+            if let Some(msg) = self.reassembler.push(frame)? {
+                return Ok(Some(msg));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder for MessageCodec {
+    type Item = Message;
+    type Error = Error;
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut frame = Frame::from(item);
+        if let Some(ref mut deflater) = self.deflater {
+            if frame.opcode == OpCode::Text || frame.opcode == OpCode::Binary {
+                frame.payload = deflater.compress(&frame.payload)?;
+                frame.rsv1 = true;
+            }
+        }
+        match self.max_frame_size {
+            Some(size) => {
+                for fragment in frame.fragment(size) {
+                    self.codec.encode(fragment, dst)?;
+                }
+                Ok(())
+            }
+            None => self.codec.encode(frame, dst),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wsproto::CloseCode;
+
+    fn frame(opcode: OpCode, fin: bool, payload: &[u8]) -> Frame {
+        Frame {
+            fin,
+            opcode,
+            payload: BytesMut::from(payload),
+            ..Frame::default()
+        }
+    }
+
+    #[test]
+    fn test_reassemble_fragments() {
+        let mut r = Reassembler::new();
+        assert_eq!(r.push(frame(OpCode::Binary, false, b"ab")).unwrap(), None);
+        assert!(r.in_progress());
+        assert_eq!(r.push(frame(OpCode::Continue, false, b"cd")).unwrap(), None);
+        assert_eq!(
+            r.push(frame(OpCode::Continue, true, b"e")).unwrap(),
+            Some(Message::Binary(BytesMut::from(&b"abcde"[..])))
+        );
+        assert!(!r.in_progress());
+    }
+
+    #[test]
+    fn test_control_between_fragments() {
+        let mut r = Reassembler::new();
+        assert_eq!(r.push(frame(OpCode::Text, false, b"he")).unwrap(), None);
+        assert_eq!(
+            r.push(frame(OpCode::Ping, true, b"p")).unwrap(),
+            Some(Message::Ping(BytesMut::from(&b"p"[..])))
+        );
+        assert_eq!(
+            r.push(frame(OpCode::Continue, true, b"llo")).unwrap(),
+            Some(Message::Text(BytesMut::from(&b"hello"[..])))
+        );
+    }
+
+    #[test]
+    fn test_continue_without_start() {
+        let mut r = Reassembler::new();
+        assert!(r.push(frame(OpCode::Continue, true, b"x")).is_err());
+    }
+
+    #[test]
+    fn test_data_frame_mid_message() {
+        let mut r = Reassembler::new();
+        assert_eq!(r.push(frame(OpCode::Text, false, b"x")).unwrap(), None);
+        assert!(r.push(frame(OpCode::Binary, true, b"y")).is_err());
+    }
+
+    #[test]
+    fn test_utf8_across_fragments() {
+        let mut r = Reassembler::new();
+        // U+00E9 is split between the two fragments
+        assert_eq!(r.push(frame(OpCode::Text, false, b"caf\xc3")).unwrap(), None);
+        assert_eq!(
+            r.push(frame(OpCode::Continue, true, b"\xa9")).unwrap(),
+            Some(Message::Text(BytesMut::from("caf\u{e9}")))
+        );
+    }
+
+    #[test]
+    fn test_utf8_invalid() {
+        let mut r = Reassembler::new();
+        assert_eq!(r.push(frame(OpCode::Text, false, b"ok")).unwrap(), None);
+        let err = r.push(frame(OpCode::Continue, false, b"\xff")).unwrap_err();
+        assert_eq!(err.close_code(), CloseCode::Invalid);
+
+        let mut r = Reassembler::new();
+        assert_eq!(r.push(frame(OpCode::Text, false, b"\xe2\x82")).unwrap(), None);
+        assert!(r.push(frame(OpCode::Continue, true, b"")).is_err());
+
+        let mut r = Reassembler::new();
+        assert!(r.push(frame(OpCode::Binary, true, b"\xff")).is_ok());
+    }
+
+    #[test]
+    fn test_codec_deflate() {
+        let config = DeflateConfig::default();
+        let mut client = MessageCodec::new(WsCodec::client()).deflate(&config);
+        let mut codec = MessageCodec::new(WsCodec::new()).deflate(&config);
+        let msg = Message::Text(BytesMut::from("compress me, compress me, compress me"));
+        let mut bytes = BytesMut::new();
+        client.encode(msg.clone(), &mut bytes).unwrap();
+        // FIN and RSV1 set on a Text frame
+        assert_eq!(bytes[0], 0xc1);
+        assert_eq!(codec.decode(&mut bytes).unwrap(), Some(msg));
+
+        let mut r = Reassembler::new();
+        assert!(r.push(Frame { rsv1: true, ..frame(OpCode::Binary, true, b"x") }).is_err());
+    }
+
+    #[test]
+    fn test_codec_max_frame_size() {
+        let mut codec = MessageCodec::new(WsCodec::new()).max_frame_size(2);
+        let mut bytes = BytesMut::new();
+        codec.encode(Message::Binary(BytesMut::from(&b"abcde"[..])), &mut bytes).unwrap();
+        assert_eq!(
+            &bytes[..],
+            &b"\x02\x02ab\x00\x02cd\x80\x01e"[..]
+        );
+        // control frames are never split
+        let mut bytes = BytesMut::new();
+        codec.encode(Message::Ping(BytesMut::from(&b"ping"[..])), &mut bytes).unwrap();
+        assert_eq!(&bytes[..], &b"\x89\x04ping"[..]);
+    }
+
+    #[test]
+    fn test_codec_decode_fragmented() {
+        // masked with an all zero key
+        let bytes: [u8; 19] = [
+            0x02, 0x81, 0, 0, 0, 0, 0x61,
+            0x89, 0x80, 0, 0, 0, 0,
+            0x80, 0x81, 0, 0, 0, 0,
+        ];
+        let mut codec = MessageCodec::new(WsCodec::new());
+        let mut bytes = BytesMut::from(&bytes[..]);
+        assert_eq!(
+            codec.decode(&mut bytes).unwrap(),
+            Some(Message::Ping(BytesMut::new()))
+        );
+        assert!(codec.decode(&mut bytes).unwrap().is_none());
+        bytes.extend_from_slice(&[0x62]);
+        assert_eq!(
+            codec.decode(&mut bytes).unwrap(),
+            Some(Message::Binary(BytesMut::from(&b"ab"[..])))
+        );
+    }
+}
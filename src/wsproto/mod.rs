@@ -1,8 +1,13 @@
+mod deflate;
+mod error;
 mod frame;
+mod message;
 mod session;
 mod wscodec;
 
-pub use frame::{Frame, OpCode};
-pub use session::SessionState;
-pub use ws::{CloseCode, Error, ErrorKind};
-pub use wscodec::WsCodec;
+pub use deflate::DeflateConfig;
+pub use error::{CloseCode, Error, ProtocolError};
+pub use frame::{CloseFrame, Frame, OpCode};
+pub use message::{Message, MessageCodec};
+pub use session::{Connection, Handler, KeepAlive, Outbox};
+pub use wscodec::{Limits, Role, WsCodec};
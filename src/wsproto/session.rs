@@ -1,24 +1,106 @@
-use crate::wsproto::frame::Frame;
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
 
-trait Handler {
-    fn on_start();
-    fn on_message();
-    fn on_error();
-    fn on_close();
+use bytes::{BigEndian, ByteOrder, BytesMut};
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::sync::mpsc;
+use tokio::codec::Framed;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::{Delay, Interval};
+
+use super::{CloseCode, CloseFrame, Error, Message, MessageCodec};
+
+/// Application side of a WebSocket connection, driven by a `Session`.
+///
+/// Pings and the closing handshake are answered by the session, so handlers
+/// only ever see Text and Binary messages.
+pub trait Handler {
+    /// Called once the connection is open.
+    fn on_start(&mut self, _out: &mut Outbox) {}
+    /// Called for every Text or Binary message. Returning an error fails the
+    /// connection with the matching close code.
+    fn on_message(&mut self, out: &mut Outbox, msg: Message) -> Result<(), Error>;
+    /// Called when the connection fails, right before `on_close`.
+    fn on_error(&mut self, _err: &Error) {}
+    /// Called exactly once, when the connection is closed for whatever reason,
+    /// including a peer that stopped answering pings. Anything tied to the
+    /// connection, such as bridged flows, should be released here.
+    fn on_close(&mut self, _code: CloseCode, _reason: &str) {}
+}
+
+/// Messages a handler wants sent to the peer.
+pub struct Outbox {
+    queue: VecDeque<Message>,
+    close: Option<CloseFrame>,
 }
 
+impl Outbox {
+    fn new() -> Self {
+        Outbox {
+            queue: VecDeque::new(),
+            close: None,
+        }
+    }
+
+    pub fn send(&mut self, msg: Message) {
+        self.queue.push_back(msg);
+    }
+
+    /// Starts the closing handshake once the handler returns.
+    // for handlers; the bridge's own has no reason to close yet
+    #[allow(dead_code)]
+    pub fn close(&mut self, code: CloseCode, reason: &str) {
+        self.close = Some(CloseFrame {
+            code,
+            reason: reason.to_owned(),
+        });
+    }
+}
+
+/// How often to ping the peer and how many unanswered pings make it dead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeepAlive {
+    pub interval: Duration,
+    pub max_missed: u32,
+}
+
+impl Default for KeepAlive {
+    fn default() -> KeepAlive {
+        KeepAlive {
+            interval: Duration::from_secs(30),
+            max_missed: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SessionState {
     Connecting,
     Open,
-    Close,
+    /// We sent a Close and are waiting for the peer's.
+    Closing,
+    Closed,
 }
 
-struct Session<H>
+/// Connection state machine between the decoded messages and a `Handler`.
+pub struct Session<H>
 where
     H: Handler,
 {
     state: SessionState,
     handler: H,
+    outbox: Outbox,
+    /// Code of the Close we sent, reported if the peer never answers it.
+    close_sent: Option<CloseCode>,
+    /// Pings waiting for their pong, oldest first, by the id in their payload.
+    pending_pings: VecDeque<(u64, Instant)>,
+    next_ping_id: u64,
+    missed_pings: u32,
+    /// Smoothed round trip time, estimated from pongs.
+    rtt: Option<Duration>,
+    /// The peer stopped answering pings and was given up on.
+    abandoned: bool,
 }
 
 impl<H> Session<H>
@@ -29,7 +111,691 @@ where
         Session {
             state: SessionState::Connecting,
             handler: h,
+            outbox: Outbox::new(),
+            close_sent: None,
+            pending_pings: VecDeque::new(),
+            next_ping_id: 0,
+            missed_pings: 0,
+            rtt: None,
+            abandoned: false,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// Whether the peer was found dead by keepalive. Nothing queued for it
+    /// is worth writing, the stream can be dropped right away.
+    pub fn abandoned(&self) -> bool {
+        self.abandoned
+    }
+
+    #[cfg(test)]
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn start(&mut self) {
+        if self.state == SessionState::Connecting {
+            self.state = SessionState::Open;
+            self.handler.on_start(&mut self.outbox);
+            self.after_handler();
+        }
+    }
+
+    pub fn on_message(&mut self, msg: Message) {
+        match msg {
+            Message::Ping(payload) => {
+                if self.state == SessionState::Open {
+                    self.outbox.send(Message::Pong(payload));
+                }
+            }
+            Message::Pong(payload) => self.on_pong(&payload, Instant::now()),
+            Message::Close(close) => self.on_close_received(close),
+            msg => {
+                // data arriving after our Close is dropped
+                if self.state == SessionState::Open {
+                    match self.handler.on_message(&mut self.outbox, msg) {
+                        Ok(()) => self.after_handler(),
+                        Err(e) => self.fail(e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the next keepalive ping, first counting the previous one as
+    /// missed if it is still unanswered. Too many misses and the peer is
+    /// considered dead: the session closes without waiting for it, and
+    /// without sending it anything more.
+    pub fn on_keepalive_tick(&mut self, keepalive: &KeepAlive, now: Instant) {
+        if self.state != SessionState::Open {
+            return;
+        }
+        if !self.pending_pings.is_empty() {
+            self.missed_pings += 1;
+            if self.missed_pings >= keepalive.max_missed {
+                let err = Error::from(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("peer missed {} pings", self.missed_pings),
+                ));
+                self.handler.on_error(&err);
+                self.finish(CloseCode::Abnormal, &err.to_string());
+                self.outbox.queue.clear();
+                self.abandoned = true;
+                return;
+            }
+        }
+        let id = self.next_ping_id;
+        self.next_ping_id += 1;
+        let mut payload = BytesMut::from(&[0u8; 8][..]);
+        BigEndian::write_u64(&mut payload, id);
+        self.outbox.send(Message::Ping(payload));
+        self.pending_pings.push_back((id, now));
+    }
+
+    /// Matches a pong to its ping. Unsolicited pongs are ignored.
+    fn on_pong(&mut self, payload: &[u8], now: Instant) {
+        if payload.len() != 8 {
+            return;
+        }
+        let id = BigEndian::read_u64(payload);
+        let sent = match self.pending_pings.iter().find(|(pending, _)| *pending == id) {
+            Some(&(_, sent)) => sent,
+            None => return,
+        };
+        // a pong also answers every ping sent before it
+        self.pending_pings.retain(|(pending, _)| *pending > id);
+        self.missed_pings = 0;
+        let sample = now - sent;
+        self.rtt = Some(match self.rtt {
+            // same smoothing as TCP's SRTT
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    fn after_handler(&mut self) {
+        if let Some(close) = self.outbox.close.take() {
+            self.close(close.code, &close.reason);
+        }
+    }
+
+    fn on_close_received(&mut self, close: Option<CloseFrame>) {
+        let (code, reason) = match close {
+            Some(ref c) => (c.code, c.reason.clone()),
+            None => (CloseCode::Status, String::new()),
+        };
+        if !matches!(code, CloseCode::Normal | CloseCode::Away | CloseCode::Status) {
+            // the peer is reporting a failure on its side
+            self.handler.on_error(&Error::CloseReceived(code));
+        }
+        match self.state {
+            SessionState::Connecting | SessionState::Open => {
+                // echo the peer's close back to complete the closing handshake
+                let echo = close.map(|c| CloseFrame {
+                    code: c.code,
+                    reason: String::new(),
+                });
+                self.outbox.send(Message::Close(echo));
+                self.finish(code, &reason);
+            }
+            SessionState::Closing => self.finish(code, &reason),
+            SessionState::Closed => (),
+        }
+    }
+
+    /// Starts the closing handshake.
+    pub fn close(&mut self, code: CloseCode, reason: &str) {
+        match self.state {
+            SessionState::Connecting | SessionState::Open => {
+                self.outbox.send(Message::Close(Some(CloseFrame {
+                    code,
+                    reason: reason.to_owned(),
+                })));
+                self.close_sent = Some(code);
+                self.state = SessionState::Closing;
+            }
+            _ => (),
+        }
+    }
+
+    /// Fails the connection: the peer gets a Close with the code matching
+    /// `err`, and no reply is waited for.
+    pub fn fail(&mut self, err: Error) {
+        if self.state == SessionState::Closed {
+            return;
+        }
+        self.handler.on_error(&err);
+        let code = err.close_code();
+        let reason = err.to_string();
+        if self.state != SessionState::Closing {
+            self.outbox.send(Message::Close(Some(CloseFrame {
+                code,
+                reason: reason.clone(),
+            })));
+        }
+        self.finish(code, &reason);
+    }
+
+    /// The peer did not answer our Close in time.
+    pub fn on_close_timeout(&mut self) {
+        let code = self.close_sent.unwrap_or(CloseCode::Abnormal);
+        self.finish(code, "");
+    }
+
+    /// The transport is gone, with `err` if it failed.
+    pub fn on_disconnect(&mut self, err: Option<&Error>) {
+        if self.state == SessionState::Closed {
+            return;
+        }
+        if let Some(e) = err {
+            self.handler.on_error(e);
+        }
+        self.finish(CloseCode::Abnormal, "");
+    }
+
+    fn finish(&mut self, code: CloseCode, reason: &str) {
+        self.state = SessionState::Closed;
+        self.handler.on_close(code, reason);
+    }
+
+    pub fn next_outgoing(&mut self) -> Option<Message> {
+        self.outbox.queue.pop_front()
+    }
+
+    /// Messages waiting to be written.
+    pub fn queued(&self) -> usize {
+        self.outbox.queue.len()
+    }
+
+    /// Queues a message from outside the handler. Dropped unless the
+    /// connection is open, as nothing may follow a Close.
+    pub fn send(&mut self, msg: Message) {
+        if self.state == SessionState::Open {
+            self.outbox.send(msg);
+        }
+    }
+
+    fn requeue_outgoing(&mut self, msg: Message) {
+        self.outbox.queue.push_front(msg);
+    }
+}
+
+/// How long a closing connection waits for the peer's Close, or for our own
+/// to be written, before the stream is dropped.
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many messages may wait for the writer before the connection stops
+/// taking more from the peer and from senders.
+const DEFAULT_MAX_QUEUED: usize = 64;
+
+/// Future running a `Session` over a framed stream until the connection closes.
+pub struct Connection<S, H>
+where
+    S: AsyncRead + AsyncWrite,
+    H: Handler,
+{
+    framed: Framed<S, MessageCodec>,
+    session: Session<H>,
+    close_timeout: Duration,
+    close_deadline: Option<Delay>,
+    keepalive: Option<(KeepAlive, Interval)>,
+    max_queued: usize,
+    outgoing: Option<(mpsc::Sender<Message>, mpsc::Receiver<Message>)>,
+}
+
+impl<S, H> Connection<S, H>
+where
+    S: AsyncRead + AsyncWrite,
+    H: Handler,
+{
+    pub fn new(framed: Framed<S, MessageCodec>, handler: H) -> Self {
+        let mut session = Session::new(handler);
+        session.start();
+        Connection {
+            framed,
+            session,
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            close_deadline: None,
+            keepalive: None,
+            max_queued: DEFAULT_MAX_QUEUED,
+            outgoing: None,
+        }
+    }
+
+    /// Pings the peer on a schedule and closes the connection when it stops answering.
+    pub fn keepalive(mut self, keepalive: KeepAlive) -> Self {
+        let interval = Interval::new(Instant::now() + keepalive.interval, keepalive.interval);
+        self.keepalive = Some((keepalive, interval));
+        self
+    }
+
+    #[cfg(test)]
+    pub fn close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = timeout;
+        self
+    }
+
+    pub fn max_queued(mut self, max_queued: usize) -> Self {
+        self.max_queued = max_queued;
+        self
+    }
+
+    /// A handle for sending messages from other tasks, e.g. datagrams read
+    /// off a bridged socket. Messages are written in the order they were sent,
+    /// after anything already queued.
+    ///
+    /// At most `capacity` messages wait in the channel while the peer is slow
+    /// to read. Sending through the `Sink` then waits for room; `try_send`
+    /// fails with a full error instead, for producers that would rather drop.
+    /// Once the connection is gone, sends fail as disconnected.
+    pub fn sender(&mut self, capacity: usize) -> mpsc::Sender<Message> {
+        let (tx, _) = self.outgoing.get_or_insert_with(|| mpsc::channel(capacity));
+        tx.clone()
+    }
+
+    /// Writes out everything the session has queued.
+    fn flush(&mut self) -> Poll<(), Error> {
+        while let Some(msg) = self.session.next_outgoing() {
+            if let AsyncSink::NotReady(msg) = self.framed.start_send(msg)? {
+                self.session.requeue_outgoing(msg);
+                return Ok(Async::NotReady);
+            }
+        }
+        self.framed.poll_complete()
+    }
+
+    /// Moves messages from senders into the session's queue while there is
+    /// room, returning whether any were taken.
+    fn poll_outgoing(&mut self) -> bool {
+        let mut taken = false;
+        if let Some((_, ref mut rx)) = self.outgoing {
+            while self.session.state() == SessionState::Open && self.session.queued() < self.max_queued {
+                match rx.poll() {
+                    Ok(Async::Ready(Some(msg))) => {
+                        self.session.send(msg);
+                        taken = true;
+                    }
+                    // we hold a sender, so the channel never ends
+                    Ok(Async::Ready(None)) | Ok(Async::NotReady) | Err(()) => break,
+                }
+            }
+        }
+        taken
+    }
+
+    fn poll_keepalive(&mut self) -> Result<(), Error> {
+        if let Some((ref keepalive, ref mut interval)) = self.keepalive {
+            while let Async::Ready(Some(now)) = interval
+                .poll()
+                .map_err(|e| Error::Internal(format!("keepalive timer error: {:?}", e).into()))?
+            {
+                self.session.on_keepalive_tick(keepalive, now);
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_close_deadline(&mut self) -> bool {
+        let timeout = self.close_timeout;
+        let deadline = self
+            .close_deadline
+            .get_or_insert_with(|| Delay::new(Instant::now() + timeout));
+        match deadline.poll() {
+            Ok(Async::NotReady) => false,
+            // a broken timer must not keep the connection around
+            Ok(Async::Ready(())) | Err(_) => true,
+        }
+    }
+}
+
+impl<S, H> Future for Connection<S, H>
+where
+    S: AsyncRead + AsyncWrite,
+    H: Handler,
+{
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Err(e) = self.poll_keepalive() {
+            self.session.on_disconnect(Some(&e));
+            return Err(e);
+        }
+        // a dead peer is not read from nor written to, however much is queued
+        if self.session.abandoned() {
+            return Ok(Async::Ready(()));
+        }
+        loop {
+            let flushed = match self.flush() {
+                Ok(flushed) => flushed.is_ready(),
+                Err(e) => {
+                    self.session.on_disconnect(Some(&e));
+                    return Err(e);
+                }
+            };
+            match self.session.state() {
+                SessionState::Closed if flushed => return Ok(Async::Ready(())),
+                // a peer that stopped reading gets no longer to take our Close
+                // than it would to answer it
+                SessionState::Closed if self.poll_close_deadline() => return Ok(Async::Ready(())),
+                SessionState::Closed => return Ok(Async::NotReady),
+                SessionState::Closing if self.poll_close_deadline() => {
+                    self.session.on_close_timeout();
+                    return Ok(Async::Ready(()));
+                }
+                _ => (),
+            }
+            if self.poll_outgoing() {
+                continue;
+            }
+            // the writer is behind, leave the peer's messages unread until
+            // it catches up, it wakes us when the stream is writable again
+            if !flushed && self.session.queued() >= self.max_queued {
+                return Ok(Async::NotReady);
+            }
+            match self.framed.poll() {
+                Ok(Async::Ready(Some(msg))) => self.session.on_message(msg),
+                Ok(Async::Ready(None)) => {
+                    self.session.on_disconnect(None);
+                    return Ok(Async::Ready(()));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    if let Error::Io(_) = e {
+                        self.session.on_disconnect(Some(&e));
+                        return Err(e);
+                    }
+                    // the peer broke the protocol, tell it why before hanging up
+                    self.session.fail(e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use futures::executor::{self, Notify};
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use futures::future;
+    use tokio::runtime::current_thread::Runtime;
+    use crate::wsproto::WsCodec;
+
+    #[derive(Default)]
+    struct Recorder {
+        started: bool,
+        messages: Vec<Message>,
+        errors: usize,
+        closed: Option<CloseCode>,
+    }
+
+    impl Handler for Recorder {
+        fn on_start(&mut self, _out: &mut Outbox) {
+            self.started = true;
+        }
+        fn on_message(&mut self, out: &mut Outbox, msg: Message) -> Result<(), Error> {
+            match msg {
+                Message::Text(ref t) if t == "bye" => out.close(CloseCode::Normal, "bye"),
+                Message::Text(ref t) if t == "bad" => {
+                    return Err(Error::Capacity("too much".into()))
+                }
+                _ => out.send(msg.clone()),
+            }
+            self.messages.push(msg);
+            Ok(())
+        }
+        fn on_error(&mut self, _err: &Error) {
+            self.errors += 1;
+        }
+        fn on_close(&mut self, code: CloseCode, _reason: &str) {
+            assert!(self.closed.is_none());
+            self.closed = Some(code);
+        }
+    }
+
+    fn text(s: &str) -> Message {
+        Message::Text(BytesMut::from(s))
+    }
+
+    fn open_session() -> Session<Recorder> {
+        let mut session = Session::new(Recorder::default());
+        assert_eq!(session.state(), SessionState::Connecting);
+        session.start();
+        assert!(session.handler().started);
+        assert_eq!(session.state(), SessionState::Open);
+        session
+    }
+
+    #[test]
+    fn test_ping_and_messages() {
+        let mut session = open_session();
+        session.on_message(Message::Ping(BytesMut::from("p")));
+        assert_eq!(session.next_outgoing(), Some(Message::Pong(BytesMut::from("p"))));
+        session.on_message(text("hi"));
+        assert_eq!(session.next_outgoing(), Some(text("hi")));
+        assert_eq!(session.next_outgoing(), None);
+        assert_eq!(session.handler().messages, vec![text("hi")]);
+    }
+
+    #[test]
+    fn test_peer_close_is_echoed() {
+        let mut session = open_session();
+        session.on_message(Message::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: "later".to_owned(),
+        })));
+        assert_eq!(
+            session.next_outgoing(),
+            Some(Message::Close(Some(CloseFrame {
+                code: CloseCode::Away,
+                reason: String::new(),
+            })))
+        );
+        assert_eq!(session.state(), SessionState::Closed);
+        assert_eq!(session.handler().closed, Some(CloseCode::Away));
+    }
+
+    #[test]
+    fn test_handler_close() {
+        let mut session = open_session();
+        session.on_message(text("bye"));
+        assert_eq!(session.state(), SessionState::Closing);
+        match session.next_outgoing() {
+            Some(Message::Close(Some(c))) => assert_eq!(c.code, CloseCode::Normal),
+            other => panic!("expected a close, got {:?}", other),
+        }
+        // ignored while closing
+        session.on_message(text("late"));
+        assert_eq!(session.next_outgoing(), None);
+        session.on_message(Message::Close(None));
+        assert_eq!(session.next_outgoing(), None);
+        assert_eq!(session.state(), SessionState::Closed);
+        assert_eq!(session.handler().closed, Some(CloseCode::Status));
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let keepalive = KeepAlive {
+            interval: Duration::from_secs(1),
+            max_missed: 2,
+        };
+        let start = Instant::now();
+        let mut session = open_session();
+        session.on_keepalive_tick(&keepalive, start);
+        let ping = match session.next_outgoing() {
+            Some(Message::Ping(p)) => p,
+            other => panic!("expected a ping, got {:?}", other),
+        };
+        // pongs not matching an outstanding ping are ignored
+        session.on_pong(b"whatever", start + Duration::from_millis(10));
+        assert_eq!(session.rtt(), None);
+        session.on_pong(&ping, start + Duration::from_millis(80));
+        assert_eq!(session.rtt(), Some(Duration::from_millis(80)));
+
+        // one miss is tolerated, the second closes the session
+        session.on_keepalive_tick(&keepalive, start + Duration::from_secs(1));
+        session.on_keepalive_tick(&keepalive, start + Duration::from_secs(2));
+        assert_eq!(session.state(), SessionState::Open);
+        session.on_keepalive_tick(&keepalive, start + Duration::from_secs(3));
+        assert_eq!(session.state(), SessionState::Closed);
+        assert!(session.abandoned());
+        assert_eq!(session.next_outgoing(), None);
+        assert_eq!(session.handler().errors, 1);
+        assert_eq!(session.handler().closed, Some(CloseCode::Abnormal));
+    }
+
+    /// Never has anything to read, and writes until told to stall or fail.
+    #[derive(Clone, Default)]
+    struct SlowStream {
+        written: Rc<RefCell<Vec<u8>>>,
+        stalled: Rc<Cell<bool>>,
+        broken: Rc<Cell<bool>>,
+    }
+
+    impl io::Read for SlowStream {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl io::Write for SlowStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.broken.get() {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            if self.stalled.get() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.written.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for SlowStream {}
+
+    impl AsyncWrite for SlowStream {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    struct Noop;
+
+    impl Notify for Noop {
+        fn notify(&self, _id: usize) {}
+    }
+
+    #[test]
+    fn test_sender_backpressure() {
+        let stream = SlowStream::default();
+        let framed = Framed::new(stream.clone(), MessageCodec::new(WsCodec::new()));
+        let mut conn = Connection::new(framed, Recorder::default()).max_queued(2);
+        let mut tx = conn.sender(2);
+        let mut conn = executor::spawn(conn);
+        let notify = Arc::new(Noop);
+        let mut poll = || conn.poll_future_notify(&notify, 0);
+
+        // big enough that the codec's write buffer takes only two
+        let message = |i: usize| format!("m{:03}{}", i, "x".repeat(4092));
+        stream.stalled.set(true);
+        let mut sent = 0;
+        while tx.try_send(text(&message(sent))).is_ok() {
+            sent += 1;
+            assert!(poll().unwrap().is_not_ready());
+        }
+        // two buffered by the codec, two queued, the channel's two slots and
+        // our sender's own
+        assert_eq!(sent, 7);
+        assert!(tx.try_send(text("dropped")).unwrap_err().is_full());
+        assert!(stream.written.borrow().is_empty());
+
+        stream.stalled.set(false);
+        assert!(poll().unwrap().is_not_ready());
+        // unmasked text frames, written in the order they were sent
+        let expected = (0..sent)
+            .flat_map(|i| [&[0x81, 126, 0x10, 0x00][..], message(i).as_bytes()].concat())
+            .collect::<Vec<u8>>();
+        assert!(*stream.written.borrow() == expected);
+
+        // a failed write ends the connection, and with it the senders
+        tx.try_send(text("lost")).unwrap();
+        stream.broken.set(true);
+        assert!(poll().is_err());
+        drop(conn);
+        assert!(tx.try_send(text("late")).unwrap_err().is_disconnected());
+    }
+
+    #[test]
+    fn test_close_timeout_when_peer_stops_reading() {
+        let stream = SlowStream::default();
+        stream.stalled.set(true);
+        let framed = Framed::new(stream.clone(), MessageCodec::new(WsCodec::new()));
+        let conn = Connection::new(framed, Recorder::default()).close_timeout(Duration::from_millis(20));
+        let mut conn = executor::spawn(conn);
+        let notify = Arc::new(Noop);
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(future::lazy(|| {
+            // a protocol error queues a Close the stalled peer never takes
+            conn.get_mut().session.on_message(text("bad"));
+            assert!(conn.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+            Ok::<_, ()>(())
+        })).unwrap();
+        let waited = Delay::new(Instant::now() + Duration::from_millis(50));
+        rt.block_on(waited).unwrap();
+        let done = rt.block_on(future::lazy(|| conn.poll_future_notify(&notify, 0))).unwrap();
+        assert!(done.is_ready());
+        assert!(stream.written.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_keepalive_drops_stalled_peer() {
+        let stream = SlowStream::default();
+        stream.stalled.set(true);
+        let framed = Framed::new(stream.clone(), MessageCodec::new(WsCodec::new()));
+        let keepalive = KeepAlive {
+            interval: Duration::from_millis(10),
+            max_missed: 1,
+        };
+        // long enough that only giving up on the peer ends the connection in time
+        let conn = Connection::new(framed, Recorder::default())
+            .keepalive(keepalive)
+            .close_timeout(Duration::from_secs(60));
+        let deadline = Delay::new(Instant::now() + Duration::from_secs(2));
+        let mut rt = Runtime::new().unwrap();
+        match rt.block_on(conn.select2(deadline)) {
+            Ok(future::Either::A(_)) => (),
+            _ => panic!("connection outlived its dead peer"),
+        }
+        // the unanswered ping was never written, and the stream is gone
+        assert!(stream.written.borrow().is_empty());
+        assert_eq!(Rc::strong_count(&stream.written), 1);
+    }
+
+    #[test]
+    fn test_handler_error_fails() {
+        let mut session = open_session();
+        session.on_message(text("bad"));
+        match session.next_outgoing() {
+            Some(Message::Close(Some(c))) => assert_eq!(c.code, CloseCode::Size),
+            other => panic!("expected a close, got {:?}", other),
         }
+        assert_eq!(session.state(), SessionState::Closed);
+        assert_eq!(session.handler().errors, 1);
+        assert_eq!(session.handler().closed, Some(CloseCode::Size));
+        session.on_disconnect(None);
+        assert_eq!(session.handler().errors, 1);
     }
-    pub fn on_frame(frame: Frame) {}
 }
@@ -1,107 +1,290 @@
 extern crate bytes;
 extern crate tokio_codec;
-extern crate ws;
+extern crate rand;
 
-use super::{Error, ErrorKind, Frame, OpCode};
+use super::frame::apply_mask;
+use super::{Error, Frame, OpCode, ProtocolError};
 
-use bytes::{Buf, BufMut, Bytes, BytesMut, IntoBuf};
+use bytes::{BigEndian, BufMut, ByteOrder, BytesMut};
 use tokio::codec::{Decoder, Encoder};
 
-pub struct WsCodec;
+/// Most read buffer space set aside at once for a payload still arriving.
+const MAX_RESERVE: usize = 64 * 1024;
+
+/// Which end of the connection a codec is on, which decides the masking rules.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    /// Sends unmasked frames and expects masked ones.
+    Server,
+    /// Masks every frame it sends with a fresh key and rejects masked frames.
+    Client,
+}
+
+/// Size limits a codec enforces on what the peer sends.
+///
+/// Each limit is checked as soon as a frame header has been parsed, before
+/// the payload is waited for. Violations are reported as `Error::Capacity`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    /// Largest payload of a single frame.
+    pub max_frame_payload: u64,
+    /// Largest message, summed over all of its fragments.
+    pub max_message_size: u64,
+    /// Most bytes held in the read buffer while waiting for a frame to complete.
+    pub max_buffered: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_frame_payload: 16 * (1 << 20),
+            max_message_size: 64 * (1 << 20),
+            max_buffered: 32 * (1 << 20),
+        }
+    }
+}
+
+/// Header of a frame whose payload has not fully arrived yet.
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    rsv2: bool,
+    rsv3: bool,
+    opcode: OpCode,
+    mask: Option<[u8; 4]>,
+    len: u64,
+}
+
+/// Where the decoder is within the current frame, so that a partially
+/// received frame is never parsed twice.
+enum DecodeState {
+    Header,
+    Payload(FrameHeader),
+}
+
+pub struct WsCodec {
+    state: DecodeState,
+    role: Role,
+    strict: bool,
+    /// RSV bits given a meaning by a negotiated extension.
+    reserved_bits: u8,
+    limits: Limits,
+    /// Payload received so far for the fragmented message in progress.
+    message_len: u64,
+}
 
 impl WsCodec {
+    /// A server side codec.
     pub fn new() -> Self {
-        WsCodec
+        WsCodec {
+            state: DecodeState::Header,
+            role: Role::Server,
+            strict: false,
+            reserved_bits: 0,
+            limits: Limits::default(),
+            message_len: 0,
+        }
     }
-}
 
-static MAX_PAYLOAD_LENGTH: u64 = 5 * (1 << 30);
+    /// A client side codec.
+    pub fn client() -> Self {
+        WsCodec {
+            role: Role::Client,
+            ..WsCodec::new()
+        }
+    }
 
-impl Decoder for WsCodec {
-    type Item = Frame;
-    type Error = Error;
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let mut buf = src.clone().into_buf();
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Rejects frames that violate RFC 6455 instead of passing them on.
+    ///
+    /// The masking rules are enforced strict or not: a server rejects
+    /// unmasked frames and a client rejects masked ones.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Allows the given RSV bits (as masked from the first header byte) in
+    /// strict mode.
+    pub fn reserved_bits(mut self, bits: u8) -> Self {
+        self.reserved_bits = bits & 0x70;
+        self
+    }
+
+    /// Parses and consumes a frame header once all of it is in `src`.
+    fn decode_header(&mut self, src: &mut BytesMut) -> Result<Option<FrameHeader>, Error> {
         // at least header is ready
         if src.len() < 2 {
             return Ok(None);
         }
-        // parse header
-        let first: u8 = buf.get_u8();
-        let second: u8 = buf.get_u8();
-
-        // head
-        let fin = first & 0x80 != 0;
-        let rsv1 = first & 0x40 != 0;
-        let rsv2 = first & 0x20 != 0;
-        let rsv3 = first & 0x10 != 0;
-        let opcode = OpCode::from(first & 0x0F);
-
+        let first = src[0];
+        let second = src[1];
         let masked = second & 0x80 != 0;
-        let mut data_len: u64 = (second & 0x7F) as u64;
+        let len_code = second & 0x7F;
 
         // required_len
-        let mut full_head_len: u64 = 2;
+        let mut head_len: usize = 2;
         if masked {
-            full_head_len += 4
+            head_len += 4
         };
-        if data_len == 126 {
-            full_head_len += 2
-        } else if data_len == 127 {
-            full_head_len += 8
+        if len_code == 126 {
+            head_len += 2
+        } else if len_code == 127 {
+            head_len += 8
         };
-
-        if (src.len() as u64) < full_head_len {
+        if src.len() < head_len {
             return Ok(None);
         }
 
-        println!(
-            "TRACE: fin = {}, rsv1 = {}, rsv2 = {}, rsv3 = {}, masked = {}, len1 = {}",
-            fin, rsv1, rsv2, rsv3, masked, data_len
-        );
-
+        let opcode = OpCode::from(first & 0x0F);
         // parse len, mask bits
-        data_len = match data_len {
-            126 => buf.get_u16_be() as u64,
-            127 => buf.get_u64_be(),
-            _ => data_len,
+        let (len, mask_at) = match len_code {
+            126 => (BigEndian::read_u16(&src[2..4]) as u64, 4),
+            127 => (BigEndian::read_u64(&src[2..10]), 10),
+            _ => (len_code as u64, 2),
         };
-
         let mask = if masked {
             let mut mask_buf: [u8; 4] = [0; 4];
-            buf.take(4).copy_to_slice(&mut mask_buf);
+            mask_buf.copy_from_slice(&src[mask_at..mask_at + 4]);
             Some(mask_buf)
         } else {
             None
         };
 
-        // check data length
-        if data_len > MAX_PAYLOAD_LENGTH {
-            return Err(Error::new(
-                ErrorKind::Protocol,
-                format!(
-                    "Rejected frame with payload length exceeding defined max: {}.",
-                    MAX_PAYLOAD_LENGTH
-                ),
-            ));
-        }
-        if src.len() < (full_head_len + data_len) as usize {
-            return Ok(None);
+        if self.strict {
+            self.validate_header(first, &opcode, len_code, len)?;
         }
+        if masked && self.role == Role::Client {
+            return Err(ProtocolError::MaskedFrame.into());
+        }
+        if !masked && self.role == Role::Server {
+            return Err(ProtocolError::UnmaskedFrame.into());
+        }
+        // check data length
+        self.check_limits(&opcode, head_len as u64, len)?;
 
-        // data
-        src.split_to(full_head_len as usize);
-        let mut data = src.split_to(data_len as usize);
-
-        let frame = Frame {
+        let fin = first & 0x80 != 0;
+        match opcode {
+            OpCode::Continue => self.message_len += len,
+            OpCode::Text | OpCode::Binary => self.message_len = len,
+            _ => (),
+        }
+        if fin && !opcode.is_control() {
+            self.message_len = 0;
+        }
+        src.advance(head_len);
+        Ok(Some(FrameHeader {
             fin,
-            rsv1,
-            rsv2,
-            rsv3,
+            rsv1: first & 0x40 != 0,
+            rsv2: first & 0x20 != 0,
+            rsv3: first & 0x10 != 0,
             opcode,
             mask,
-            payload: data,
+            len,
+        }))
+    }
+
+    fn check_limits(&self, opcode: &OpCode, head_len: u64, data_len: u64) -> Result<(), Error> {
+        let too_big = |what: &str, max: u64| {
+            Err(Error::Capacity(
+                format!("Rejected {} exceeding defined max: {}.", what, max).into(),
+            ))
+        };
+        if data_len > self.limits.max_frame_payload {
+            return too_big("frame payload", self.limits.max_frame_payload);
+        }
+        let message_len = match opcode {
+            OpCode::Continue => self.message_len.saturating_add(data_len),
+            OpCode::Text | OpCode::Binary => data_len,
+            _ => 0,
         };
+        if message_len > self.limits.max_message_size {
+            return too_big("message", self.limits.max_message_size);
+        }
+        if head_len + data_len > self.limits.max_buffered as u64 {
+            return too_big("buffered frame", self.limits.max_buffered as u64);
+        }
+        Ok(())
+    }
+
+    fn validate_header(
+        &self,
+        first: u8,
+        opcode: &OpCode,
+        len_code: u8,
+        data_len: u64,
+    ) -> Result<(), ProtocolError> {
+        if first & 0x70 & !self.reserved_bits != 0 {
+            return Err(ProtocolError::ReservedBits);
+        }
+        if let OpCode::Bad(v) = opcode {
+            return Err(ProtocolError::BadOpCode(*v));
+        }
+        match len_code {
+            126 if data_len < 126 => return Err(ProtocolError::NonMinimalLength),
+            127 if data_len >> 63 != 0 => return Err(ProtocolError::LengthOverflow),
+            127 if data_len < 65536 => return Err(ProtocolError::NonMinimalLength),
+            _ => (),
+        }
+        match opcode {
+            OpCode::Close | OpCode::Ping | OpCode::Pong => {
+                if data_len > 125 {
+                    return Err(ProtocolError::ControlFrameTooLong(data_len));
+                }
+                if first & 0x80 == 0 {
+                    return Err(ProtocolError::FragmentedControlFrame);
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for WsCodec {
+    type Item = Frame;
+    type Error = Error;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let DecodeState::Header = self.state {
+            match self.decode_header(src)? {
+                Some(header) => self.state = DecodeState::Payload(header),
+                None => return Ok(None),
+            }
+        }
+        let len = match self.state {
+            DecodeState::Payload(ref header) => header.len as usize,
+            DecodeState::Header => unreachable!(),
+        };
+        if src.len() < len {
+            // grow with what arrives rather than trusting the declared length
+            src.reserve((len - src.len()).min(MAX_RESERVE));
+            return Ok(None);
+        }
+        let header = match std::mem::replace(&mut self.state, DecodeState::Header) {
+            DecodeState::Payload(header) => header,
+            DecodeState::Header => unreachable!(),
+        };
+        let mut frame = Frame {
+            fin: header.fin,
+            rsv1: header.rsv1,
+            rsv2: header.rsv2,
+            rsv3: header.rsv3,
+            opcode: header.opcode,
+            mask: header.mask,
+            payload: src.split_to(len),
+        };
+        // unmask in place, frames are handed out unmasked
+        if let Some(m) = frame.mask.take() {
+            apply_mask(m, &mut frame.payload);
+        }
         Ok(Some(frame))
     }
 }
@@ -110,16 +293,22 @@ impl Encoder for WsCodec {
     type Item = Frame;
     type Error = Error;
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // clients mask every frame with a fresh key, servers never mask
+        let mask = match self.role {
+            Role::Client => Some(rand::random::<[u8; 4]>()),
+            Role::Server => None,
+        };
+        dst.reserve(14 + item.payload.len());
         // head
         let x: u8 = item.opcode.into();
-        let first: u8 = (if item.fin { 0x80u8 } else { 0x00u8 }) as u8
-            | (if item.rsv1 { 0x40u8 } else { 0x00u8 }) as u8
-            | (if item.rsv2 { 0x20u8 } else { 0x00u8 }) as u8
-            | (if item.rsv3 { 0x10u8 } else { 0x00u8 }) as u8
+        let first: u8 = (if item.fin { 0x80u8 } else { 0x00u8 })
+            | (if item.rsv1 { 0x40u8 } else { 0x00u8 })
+            | (if item.rsv2 { 0x20u8 } else { 0x00u8 })
+            | (if item.rsv3 { 0x10u8 } else { 0x00u8 })
             | x;
         dst.put_u8(first);
         // mask & payload_length
-        let mask_bit: u8 = if item.mask.is_some() { 0x80 } else { 0x00 };
+        let mask_bit: u8 = if mask.is_some() { 0x80 } else { 0x00 };
         match item.payload.len() {
             i if i < 126 => dst.put_u8(mask_bit | (i as u8)),
             i if i < 65536 => {
@@ -131,11 +320,15 @@ impl Encoder for WsCodec {
                 dst.put_u64_be(i as u64);
             }
         };
-        if let Some(m) = item.mask {
+        if let Some(m) = mask {
             dst.put_slice(&m);
         }
         // payload
+        let start = dst.len();
         dst.put_slice(&item.payload[..]);
+        if let Some(m) = mask {
+            apply_mask(m, &mut dst[start..]);
+        }
         Ok(())
     }
 }
@@ -143,39 +336,154 @@ impl Encoder for WsCodec {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::wsproto::CloseCode;
 
     #[test]
     fn test() {
-        let bytes: [u8; 6] = [0x01, 0x01, 0x01, 0x00, 0x00, 0x00];
+        let bytes: [u8; 10] = [0x01, 0x81, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
 
         let mut codec = WsCodec::new();
         let mut bytes = BytesMut::from(&bytes[..]);
         if let Ok(Some(v)) = codec.decode(&mut bytes) {
-            assert_eq!(v.fin, false);
-            assert_eq!(v.rsv1, false);
-            assert_eq!(v.rsv2, false);
-            assert_eq!(v.rsv2, false);
+            assert!(!v.fin);
+            assert!(!v.rsv1);
+            assert!(!v.rsv2);
+            assert!(!v.rsv3);
             assert_eq!(v.opcode, OpCode::Text);
             assert_eq!(v.mask, None);
             assert_eq!(v.payload, BytesMut::from(&b"\x01"[..]));
         } else {
-            assert!(false);
+            panic!("decode failed");
         }
         assert_eq!(bytes.len(), 3);
     }
 
+    #[test]
+    fn test_strict() {
+        let reject = |bytes: &[u8]| {
+            let mut codec = WsCodec::new().strict(true);
+            match codec.decode(&mut BytesMut::from(bytes)) {
+                Err(Error::Protocol(e)) => Some(e),
+                Err(_) => None,
+                Ok(_) => None,
+            }
+        };
+        // masked, empty binary frame is fine
+        let mut codec = WsCodec::new().strict(true);
+        let ok = codec.decode(&mut BytesMut::from(&b"\x82\x80\x00\x00\x00\x00"[..]));
+        assert!(ok.unwrap().is_some());
+
+        assert_eq!(reject(b"\x82\x00"), Some(ProtocolError::UnmaskedFrame));
+        // servers refuse unmasked frames even when not strict
+        let mut codec = WsCodec::new();
+        assert!(codec.decode(&mut BytesMut::from(&b"\x82\x00"[..])).is_err());
+        assert_eq!(reject(b"\xc2\x80\x00\x00\x00\x00"), Some(ProtocolError::ReservedBits));
+        assert_eq!(reject(b"\x83\x80\x00\x00\x00\x00"), Some(ProtocolError::BadOpCode(3)));
+        assert_eq!(reject(b"\x09\x80\x00\x00\x00\x00"), Some(ProtocolError::FragmentedControlFrame));
+        assert_eq!(
+            reject(b"\x89\xfe\x00\x7e\x00\x00\x00\x00"),
+            Some(ProtocolError::ControlFrameTooLong(126))
+        );
+        assert_eq!(
+            reject(b"\x82\xfe\x00\x01\x00\x00\x00\x00"),
+            Some(ProtocolError::NonMinimalLength)
+        );
+        assert_eq!(
+            reject(b"\x82\xff\x00\x00\x00\x00\x00\x00\xff\xff\x00\x00\x00\x00"),
+            Some(ProtocolError::NonMinimalLength)
+        );
+        assert_eq!(
+            reject(b"\x82\xff\x80\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00"),
+            Some(ProtocolError::LengthOverflow)
+        );
+    }
+
+    #[test]
+    fn test_client_masks() {
+        let mut client = WsCodec::client();
+        let mut server = WsCodec::new().strict(true);
+        let mut bytes = BytesMut::new();
+        client.encode(Frame::text("hello"), &mut bytes).unwrap();
+        assert_eq!(bytes[1] & 0x80, 0x80);
+        assert_ne!(&bytes[6..], b"hello");
+
+        let frame = server.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(frame.mask, None);
+        assert_eq!(frame.payload, BytesMut::from(&b"hello"[..]));
+
+        // servers send unmasked frames, which clients refuse when masked
+        server.encode(Frame::text("hi"), &mut bytes).unwrap();
+        assert_eq!(bytes[1] & 0x80, 0x00);
+        assert!(client.decode(&mut bytes).unwrap().is_some());
+        let mut masked = BytesMut::from(&b"\x81\x80\x00\x00\x00\x00"[..]);
+        assert!(client.decode(&mut masked).is_err());
+    }
+
+    #[test]
+    fn test_incremental() {
+        // masked "hello" arriving a few bytes at a time
+        let wire = b"\x81\x85\x01\x02\x03\x04\x69\x67\x6f\x68\x6e";
+        let mut codec = WsCodec::new();
+        let mut bytes = BytesMut::new();
+        for chunk in wire[..wire.len() - 1].chunks(3) {
+            bytes.extend_from_slice(chunk);
+            assert!(codec.decode(&mut bytes).unwrap().is_none());
+        }
+        // the header has been consumed already
+        assert_eq!(bytes.len(), 4);
+        bytes.extend_from_slice(&wire[wire.len() - 1..]);
+        let frame = codec.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Text);
+        assert_eq!(frame.payload, BytesMut::from(&b"hello"[..]));
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_frame_payload: 4,
+            max_message_size: 6,
+            max_buffered: 64,
+        };
+        let too_big = |codec: &mut WsCodec, bytes: &[u8]| match codec.decode(&mut BytesMut::from(bytes)) {
+            Err(e) => e.close_code() == CloseCode::Size,
+            Ok(_) => false,
+        };
+        // rejected from the header alone, before the payload arrives
+        let mut codec = WsCodec::new().limits(limits.clone());
+        assert!(too_big(&mut codec, b"\x82\x85\x00\x00\x00\x00"));
+
+        let mut codec = WsCodec::new().limits(limits.clone());
+        let mut bytes = BytesMut::from(&b"\x02\x84\x00\x00\x00\x00abcd\x00\x82\x00\x00\x00\x00ef"[..]);
+        assert!(codec.decode(&mut bytes).unwrap().is_some());
+        assert!(codec.decode(&mut bytes).unwrap().is_some());
+        assert!(too_big(&mut codec, b"\x80\x81\x00\x00\x00\x00"));
+
+        let mut codec = WsCodec::new().limits(Limits { max_buffered: 8, ..limits });
+        assert!(too_big(&mut codec, b"\x82\x84\x00\x00\x00\x00"));
+    }
+
+    #[test]
+    fn test_reserve_capped() {
+        // a masked frame declaring a 16 MiB payload, with none of it sent
+        let mut bytes = BytesMut::from(&b"\x82\xff\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00"[..]);
+        let mut codec = WsCodec::new();
+        assert!(codec.decode(&mut bytes).unwrap().is_none());
+        assert!(bytes.capacity() <= 2 * MAX_RESERVE);
+    }
+
     #[test]
     fn test_not_ready() {
         let mut codec = WsCodec::new();
         if let Ok(v) = codec.decode(&mut BytesMut::from(&b"\x01"[..])) {
             assert!(v.is_none())
         } else {
-            assert!(false);
+            panic!("decode failed");
         }
-        if let Ok(v) = codec.decode(&mut BytesMut::from(&b"\x01\x01"[..])) {
+        if let Ok(v) = codec.decode(&mut BytesMut::from(&b"\x01\x81"[..])) {
             assert!(v.is_none())
         } else {
-            assert!(false);
+            panic!("decode failed");
         }
     }
 }
@@ -0,0 +1,415 @@
+//! Just enough DER to read identities out of client certificates and serial
+//! numbers out of CRLs. Chains are verified by the TLS layer, CRL signatures
+//! here, with webpki.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio_rustls::webpki::{self, EndEntityCert, SignatureAlgorithm};
+
+use crate::auth::Identity;
+use crate::wsproto::Error;
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const INTEGER: u8 = 0x02;
+const OID: u8 = 0x06;
+const BOOLEAN: u8 = 0x01;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const EXPLICIT_0: u8 = 0xa0;
+const EXPLICIT_3: u8 = 0xa3;
+const SAN_DNS: u8 = 0x82;
+const SAN_URI: u8 = 0x86;
+
+/// 2.5.4.3
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+/// 2.5.29.17
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+/// 1.2.840.10045.4.3.2
+const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+/// 1.2.840.10045.4.3.3
+const OID_ECDSA_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+/// 1.2.840.113549.1.1.11
+const OID_RSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+/// 1.2.840.113549.1.1.12
+const OID_RSA_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+/// 1.2.840.113549.1.1.13
+const OID_RSA_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+/// 1.3.101.112
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+fn bad_der() -> Error {
+    Error::Auth("malformed certificate".into())
+}
+
+/// Reads DER elements one after the other.
+struct Der<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(input: &'a [u8]) -> Der<'a> {
+        Der { input }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.input.is_empty()
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.input.first().cloned()
+    }
+
+    /// Reads the next element, returning its tag and contents.
+    fn any(&mut self) -> Result<(u8, &'a [u8]), Error> {
+        let (&tag, rest) = self.input.split_first().ok_or_else(bad_der)?;
+        let (&first, mut rest) = rest.split_first().ok_or_else(bad_der)?;
+        let len = if first < 0x80 {
+            first as usize
+        } else {
+            let n = (first & 0x7f) as usize;
+            if n == 0 || n > 4 || rest.len() < n {
+                return Err(bad_der());
+            }
+            let len = rest[..n].iter().fold(0usize, |len, &b| (len << 8) | b as usize);
+            rest = &rest[n..];
+            len
+        };
+        if rest.len() < len {
+            return Err(bad_der());
+        }
+        self.input = &rest[len..];
+        Ok((tag, &rest[..len]))
+    }
+
+    /// Reads the next element, which must have the given tag.
+    fn expect(&mut self, tag: u8) -> Result<&'a [u8], Error> {
+        match self.any()? {
+            (t, contents) if t == tag => Ok(contents),
+            _ => Err(bad_der()),
+        }
+    }
+
+    /// Reads the next element, which must have the given tag, returning its
+    /// whole encoding rather than just the contents.
+    fn expect_raw(&mut self, tag: u8) -> Result<&'a [u8], Error> {
+        let start = self.input;
+        self.expect(tag)?;
+        Ok(&start[..start.len() - self.input.len()])
+    }
+
+    /// Reads the next element if it has the given tag.
+    fn optional(&mut self, tag: u8) -> Result<Option<&'a [u8]>, Error> {
+        if self.peek_tag() == Some(tag) {
+            self.expect(tag).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// The parts of a certificate used to identify its holder.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertInfo {
+    /// Serial number as encoded, for matching against CRLs.
+    pub serial: Vec<u8>,
+    /// Issuer name as encoded, which serial numbers are unique within.
+    pub issuer: Vec<u8>,
+    /// Subject name as encoded, matched against CRL issuers for CA certificates.
+    pub subject: Vec<u8>,
+    /// Subject common name.
+    pub common_name: Option<String>,
+    /// DNS names and URIs from the subjectAltName extension.
+    pub alt_names: Vec<String>,
+}
+
+impl CertInfo {
+    pub fn parse(der: &[u8]) -> Result<CertInfo, Error> {
+        let cert = Der::new(der).expect(SEQUENCE)?;
+        let mut tbs = Der::new(Der::new(cert).expect(SEQUENCE)?);
+        tbs.optional(EXPLICIT_0)?;
+        let serial = tbs.expect(INTEGER)?.to_vec();
+        tbs.expect(SEQUENCE)?; // signature algorithm
+        let issuer = tbs.expect(SEQUENCE)?.to_vec();
+        tbs.expect(SEQUENCE)?; // validity
+        let subject = tbs.expect(SEQUENCE)?;
+        let common_name = parse_common_name(subject)?;
+        let subject = subject.to_vec();
+        tbs.expect(SEQUENCE)?; // public key
+        let mut alt_names = Vec::new();
+        while !tbs.is_empty() {
+            if let (EXPLICIT_3, extensions) = tbs.any()? {
+                alt_names = parse_alt_names(Der::new(extensions).expect(SEQUENCE)?)?;
+            }
+        }
+        Ok(CertInfo { serial, issuer, subject, common_name, alt_names })
+    }
+
+    /// The identity of the holder: the first subject alternative name, or the
+    /// common name when there is none.
+    pub fn identity(&self) -> Option<Identity> {
+        self.alt_names.first().or(self.common_name.as_ref())
+            .map(|name| Identity(name.clone()))
+    }
+}
+
+fn parse_common_name(name: &[u8]) -> Result<Option<String>, Error> {
+    let mut rdns = Der::new(name);
+    while !rdns.is_empty() {
+        let mut attrs = Der::new(rdns.expect(SET)?);
+        while !attrs.is_empty() {
+            let mut attr = Der::new(attrs.expect(SEQUENCE)?);
+            if attr.expect(OID)? == OID_COMMON_NAME {
+                // UTF8String, PrintableString, IA5String and friends
+                let (_, value) = attr.any()?;
+                let value = std::str::from_utf8(value).map_err(|_| bad_der())?;
+                return Ok(Some(value.to_owned()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn parse_alt_names(extensions: &[u8]) -> Result<Vec<String>, Error> {
+    let mut extensions = Der::new(extensions);
+    while !extensions.is_empty() {
+        let mut ext = Der::new(extensions.expect(SEQUENCE)?);
+        if ext.expect(OID)? != OID_SUBJECT_ALT_NAME {
+            continue;
+        }
+        ext.optional(BOOLEAN)?;
+        let value = ext.expect(OCTET_STRING)?;
+        let mut names = Der::new(Der::new(value).expect(SEQUENCE)?);
+        let mut alt_names = Vec::new();
+        while !names.is_empty() {
+            match names.any()? {
+                (SAN_DNS, name) | (SAN_URI, name) => {
+                    alt_names.push(std::str::from_utf8(name).map_err(|_| bad_der())?.to_owned());
+                },
+                // emails, IP addresses and the rest do not name a tunnel
+                _ => (),
+            }
+        }
+        return Ok(alt_names);
+    }
+    Ok(Vec::new())
+}
+
+/// Algorithms a signature with the given algorithm OID may have been made
+/// with, the right one depending on the signer's key.
+fn signature_algs(oid: &[u8]) -> &'static [&'static SignatureAlgorithm] {
+    static ECDSA_SHA256: [&SignatureAlgorithm; 2] = [&webpki::ECDSA_P256_SHA256, &webpki::ECDSA_P384_SHA256];
+    static ECDSA_SHA384: [&SignatureAlgorithm; 2] = [&webpki::ECDSA_P384_SHA384, &webpki::ECDSA_P256_SHA384];
+    static RSA_SHA256: [&SignatureAlgorithm; 1] = [&webpki::RSA_PKCS1_2048_8192_SHA256];
+    static RSA_SHA384: [&SignatureAlgorithm; 1] = [&webpki::RSA_PKCS1_2048_8192_SHA384];
+    static RSA_SHA512: [&SignatureAlgorithm; 1] = [&webpki::RSA_PKCS1_2048_8192_SHA512];
+    static ED25519: [&SignatureAlgorithm; 1] = [&webpki::ED25519];
+    match oid {
+        OID_ECDSA_SHA256 => &ECDSA_SHA256,
+        OID_ECDSA_SHA384 => &ECDSA_SHA384,
+        OID_RSA_SHA256 => &RSA_SHA256,
+        OID_RSA_SHA384 => &RSA_SHA384,
+        OID_RSA_SHA512 => &RSA_SHA512,
+        OID_ED25519 => &ED25519,
+        _ => &[],
+    }
+}
+
+/// Reads a UTCTime or GeneralizedTime, which X.509 always gives in UTC to
+/// the second.
+fn parse_time(tag: u8, value: &[u8]) -> Result<SystemTime, Error> {
+    let value = std::str::from_utf8(value).map_err(|_| bad_der())?;
+    let digits = value.strip_suffix('Z').ok_or_else(bad_der)?;
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(bad_der());
+    }
+    let (year, rest) = match (tag, digits.len()) {
+        (UTC_TIME, 12) => {
+            let yy: i64 = digits[..2].parse().unwrap();
+            (if yy < 50 { 2000 + yy } else { 1900 + yy }, &digits[2..])
+        },
+        (GENERALIZED_TIME, 14) => (digits[..4].parse().unwrap(), &digits[4..]),
+        _ => return Err(bad_der()),
+    };
+    let field = |i: usize| rest[i..i + 2].parse::<i64>().unwrap();
+    let (month, day) = (field(0), field(2));
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(bad_der());
+    }
+    // days since the epoch of a proleptic Gregorian date
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + field(4) * 3600 + field(6) * 60 + field(8);
+    if secs < 0 {
+        return Err(bad_der());
+    }
+    Ok(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+/// The revocations a CRL makes, and what is needed to check who made them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrlInfo {
+    /// Issuer name as encoded, the CA whose certificates the CRL revokes.
+    pub issuer: Vec<u8>,
+    /// Serial numbers of the revoked certificates.
+    pub revoked: Vec<Vec<u8>>,
+    /// When the issuer promises a newer CRL, after which this one may miss
+    /// revocations.
+    pub next_update: Option<SystemTime>,
+    tbs: Vec<u8>,
+    signature_alg: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl CrlInfo {
+    pub fn parse(der: &[u8]) -> Result<CrlInfo, Error> {
+        let mut crl = Der::new(Der::new(der).expect(SEQUENCE)?);
+        let tbs_der = crl.expect_raw(SEQUENCE)?;
+        let signature_alg = Der::new(crl.expect(SEQUENCE)?).expect(OID)?.to_vec();
+        let signature = match crl.expect(BIT_STRING)?.split_first() {
+            Some((0, signature)) => signature.to_vec(),
+            _ => return Err(bad_der()),
+        };
+
+        let mut tbs = Der::new(Der::new(tbs_der).expect(SEQUENCE)?);
+        tbs.optional(INTEGER)?; // version
+        tbs.expect(SEQUENCE)?; // signature algorithm
+        let issuer = tbs.expect(SEQUENCE)?.to_vec();
+        match tbs.any()? {
+            (UTC_TIME, _) | (GENERALIZED_TIME, _) => (),
+            _ => return Err(bad_der()),
+        }
+        let next_update = match tbs.peek_tag() {
+            Some(tag @ UTC_TIME) | Some(tag @ GENERALIZED_TIME) => Some(parse_time(tag, tbs.expect(tag)?)?),
+            _ => None,
+        };
+        let mut revoked = Vec::new();
+        if let Some(entries) = tbs.optional(SEQUENCE)? {
+            let mut entries = Der::new(entries);
+            while !entries.is_empty() {
+                let mut entry = Der::new(entries.expect(SEQUENCE)?);
+                revoked.push(entry.expect(INTEGER)?.to_vec());
+            }
+        }
+        Ok(CrlInfo { issuer, revoked, next_update, tbs: tbs_der.to_vec(), signature_alg, signature })
+    }
+
+    /// Whether the CA certificate `ca` issued this CRL: the CRL names it as
+    /// issuer and is signed with its key.
+    pub fn signed_by(&self, ca: &[u8]) -> Result<bool, Error> {
+        if CertInfo::parse(ca)?.subject != self.issuer {
+            return Ok(false);
+        }
+        let ca = EndEntityCert::from(ca).map_err(|_| bad_der())?;
+        Ok(signature_algs(&self.signature_alg).iter()
+            .any(|alg| ca.verify_signature(alg, &self.tbs, &self.signature).is_ok()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::pem_blocks;
+
+    // tunnel-a has SANs, tunnel-b only a CN and is revoked by CRL
+    const CLIENT_CERT: &str = "\
+-----BEGIN CERTIFICATE-----\n\
+MIIBpDCCAUmgAwIBAgICEjQwCgYIKoZIzj0EAwIwGDEWMBQGA1UEAwwNd3Nicmcg\n\
+dGVzdCBjYTAeFw0yNjEwMTgxMDA0MDFaFw0zNjEwMTUxMDA0MDFaMCMxDjAMBgNV\n\
+BAoMBXdzYnJnMREwDwYDVQQDDAh0dW5uZWwtYTBZMBMGByqGSM49AgEGCCqGSM49\n\
+AwEHA0IABC9/8B86o1cQk+OLhEB8CRH24jYv3AGTOulk10RSl2eQuzcXiYLfb0na\n\
+g4LJAdhB2iA68TlfMYh0qlP0R6hRxUmjeDB2MDQGA1UdEQQtMCuCEHR1bm5lbC1h\n\
+LmV4YW1wbGWGF3NwaWZmZTovL3dzYnJnL3R1bm5lbC1hMB0GA1UdDgQWBBQDZS2j\n\
+k9N7Ko098gEIWCQURsxYCDAfBgNVHSMEGDAWgBRVe8PN3Sf4b3uJPKGFdvKK2uqC\n\
+3DAKBggqhkjOPQQDAgNJADBGAiEAyJ/zYZIV5PpP64bj+1WDHbKFNpJUHdxdZX41\n\
+I4dvcw4CIQC5V7m3+Lo8Rqac6WrAysrisV6j3TqR+AXHhkV5gbWanQ==\n\
+-----END CERTIFICATE-----\n";
+
+    const REVOKED_CERT: &str = "\
+-----BEGIN CERTIFICATE-----\n\
+MIIBXjCCAQOgAwIBAgICAIAwCgYIKoZIzj0EAwIwGDEWMBQGA1UEAwwNd3Nicmcg\n\
+dGVzdCBjYTAeFw0yNjEwMTgxMDA0MDFaFw0zNjEwMTUxMDA0MDFaMBMxETAPBgNV\n\
+BAMMCHR1bm5lbC1iMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEv0I86/9cP79m\n\
+0F7Z6lMwDaHgGGocEK5n0ToDWSI7TEPu1iqSH1u288FE74bCaigMs9kSgo3Nj4DY\n\
+60MIGUWpC6NCMEAwHQYDVR0OBBYEFP7EbWBob+Jo1vi4bugQ6+i9lA80MB8GA1Ud\n\
+IwQYMBaAFFV7w83dJ/hve4k8oYV28ora6oLcMAoGCCqGSM49BAMCA0kAMEYCIQCg\n\
+qMZfVnxh8vvhTQn3AQcgHWllclt7IOCvxf00m6BRngIhAIM9C5yArU/fNfjMC5Px\n\
+XUZci7fNBvVYfYZVjQdulAmJ\n\
+-----END CERTIFICATE-----\n";
+
+    const CRL: &str = "\
+-----BEGIN X509 CRL-----\n\
+MIHGMG4CAQEwCgYIKoZIzj0EAwIwGDEWMBQGA1UEAwwNd3NicmcgdGVzdCBjYRcN\n\
+MjYxMDE4MTAwNDAxWhcNMzYxMDE1MTAwNDAxWjAVMBMCAgCAFw0yNjEwMTgxMDA0\n\
+MDFaoA4wDDAKBgNVHRQEAwIBATAKBggqhkjOPQQDAgNIADBFAiEAnYxjAwvsLV9Y\n\
+31Fu/RF94xV8UZxi9m6T5yZUmp6mrk0CIGKQlNaEHrHxuKBYT1ffP6ILF1YDkrLz\n\
+YAed5BoIrRfS\n\
+-----END X509 CRL-----\n";
+
+    fn der(pem: &str, label: &str) -> Vec<u8> {
+        pem_blocks(pem, label).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_cert_info() {
+        let info = CertInfo::parse(&der(CLIENT_CERT, "CERTIFICATE")).unwrap();
+        assert_eq!(info.serial, vec![0x12, 0x34]);
+        assert_eq!(info.common_name.as_deref(), Some("tunnel-a"));
+        assert_eq!(info.alt_names, vec!["tunnel-a.example", "spiffe://wsbrg/tunnel-a"]);
+        assert_eq!(info.identity(), Some(Identity("tunnel-a.example".to_owned())));
+
+        let info = CertInfo::parse(&der(REVOKED_CERT, "CERTIFICATE")).unwrap();
+        assert!(info.alt_names.is_empty());
+        assert_eq!(info.identity(), Some(Identity("tunnel-b".to_owned())));
+
+        let mut truncated = der(CLIENT_CERT, "CERTIFICATE");
+        truncated.truncate(100);
+        assert!(CertInfo::parse(&truncated).is_err());
+    }
+
+    fn testdata(name: &str) -> Vec<u8> {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls").join(name);
+        let label = if name.ends_with(".crl") { "X509 CRL" } else { "CERTIFICATE" };
+        der(&std::fs::read_to_string(path).unwrap(), label)
+    }
+
+    #[test]
+    fn test_crl_info() {
+        let crl = CrlInfo::parse(&der(CRL, "X509 CRL")).unwrap();
+        assert_eq!(crl.revoked, vec![vec![0x00, 0x80]]);
+        let info = CertInfo::parse(&der(REVOKED_CERT, "CERTIFICATE")).unwrap();
+        assert!(crl.revoked.contains(&info.serial));
+        assert_eq!(crl.issuer, info.issuer);
+        // Oct 15 10:04:01 2036
+        assert_eq!(crl.next_update, Some(UNIX_EPOCH + Duration::from_secs(2_107_677_841)));
+
+        // only the CA named as issuer, and holding the signing key, issued a CRL
+        let crl = CrlInfo::parse(&testdata("ca.crl")).unwrap();
+        assert!(crl.signed_by(&testdata("ca.pem")).unwrap());
+        assert!(!crl.signed_by(&testdata("other.pem")).unwrap());
+        let mut forged = crl.clone();
+        let last = forged.tbs.len() - 1;
+        forged.tbs[last] ^= 1;
+        assert!(!forged.signed_by(&testdata("ca.pem")).unwrap());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let time = |tag, s: &str| parse_time(tag, s.as_bytes()).ok()
+            .map(|t| t.duration_since(UNIX_EPOCH).unwrap().as_secs());
+        assert_eq!(time(UTC_TIME, "700101000000Z"), Some(0));
+        assert_eq!(time(UTC_TIME, "000229235959Z"), Some(951_868_799));
+        assert_eq!(time(GENERALIZED_TIME, "20500101000000Z"), Some(2_524_608_000));
+        // UTCTime years from 50 are in the 1900s, which is before the epoch
+        assert_eq!(time(UTC_TIME, "500101000000Z"), None);
+        assert_eq!(time(UTC_TIME, "20500101000000Z"), None);
+        assert_eq!(time(UTC_TIME, "7001010000Z"), None);
+        assert_eq!(time(GENERALIZED_TIME, "20501301000000Z"), None);
+    }
+}